@@ -0,0 +1,215 @@
+///Animation module: decoding and encoding of animated GIFs, one full-size RGBA frame at a time.
+
+use std::io::Cursor;
+
+use gif;
+use gif::{DisposalMethod, Repeat, SetParameter};
+
+use image::{Rgba, RgbaImage};
+
+use template::{Error, Result};
+
+///The most frames read from any single GIF. Frames past this are dropped.
+pub const MAX_FRAMES: usize = 50;
+///The biggest image, or GIF canvas, that's decoded, in pixels. Every frame is decoded at this size.
+pub const MAX_PIXELS: u64 = 4096 * 4096;
+///The most pixels decoded from any single GIF, counted over every frame kept.
+pub const MAX_TOTAL_PIXELS: u64 = 64 * 1024 * 1024;
+///Delay used for frames that don't specify one, in hundredths of a second.
+const DEFAULT_DELAY: u16 = 10;
+
+///One frame of an animation, already composited onto the full canvas.
+#[derive(Clone)]
+pub struct Frame {
+    pub image: RgbaImage,
+    ///How long to show this frame, in hundredths of a second.
+    pub delay: u16,
+}
+
+///Checks the magic bytes to see whether `data` is a GIF.
+pub fn is_gif(data: &[u8]) -> bool {
+    data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")
+}
+///Decodes every frame of a GIF (up to MAX_FRAMES), applying each frame's disposal method so that
+///every returned frame is a complete picture. GIFs bigger than MAX_PIXELS, or with more than
+///MAX_TOTAL_PIXELS across their frames, are refused before they're decoded.
+pub fn decode_gif(data: &[u8]) -> Result<Vec<Frame>> {
+    let mut decoder = gif::Decoder::new(Cursor::new(data));
+    decoder.set(gif::ColorOutput::RGBA);
+    let mut reader = decoder.read_info()?;
+    let pixels = reader.width() as u64 * reader.height() as u64;
+    if pixels > MAX_PIXELS {
+        return Err(Error::Invalid(format!(
+            "GIF is too big ({}x{})",
+            reader.width(),
+            reader.height()
+        )));
+    }
+    let mut canvas = RgbaImage::new(reader.width() as u32, reader.height() as u32);
+    let mut frames = Vec::new();
+    while frames.len() < MAX_FRAMES {
+        let frame = match reader.read_next_frame()? {
+            Some(frame) => frame,
+            None => break,
+        };
+        if (frames.len() as u64 + 1) * pixels > MAX_TOTAL_PIXELS {
+            return Err(Error::Invalid(
+                "GIF has too many frames for its size".to_owned(),
+            ));
+        }
+        let previous = if frame.dispose == DisposalMethod::Previous {
+            Some(canvas.clone())
+        } else {
+            None
+        };
+        //draw the frame's sub-rectangle over the canvas, skipping transparent pixels
+        for (index, pixel) in frame.buffer.chunks(4).enumerate() {
+            let x = frame.left as u32 + index as u32 % frame.width as u32;
+            let y = frame.top as u32 + index as u32 / frame.width as u32;
+            if pixel[3] != 0 && x < canvas.width() && y < canvas.height() {
+                canvas.put_pixel(x, y, Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]));
+            }
+        }
+        frames.push(Frame {
+            image: canvas.clone(),
+            delay: if frame.delay == 0 {
+                DEFAULT_DELAY
+            } else {
+                frame.delay
+            },
+        });
+        match frame.dispose {
+            DisposalMethod::Background => {
+                for x in frame.left as u32..frame.left as u32 + frame.width as u32 {
+                    for y in frame.top as u32..frame.top as u32 + frame.height as u32 {
+                        if x < canvas.width() && y < canvas.height() {
+                            canvas.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+                        }
+                    }
+                }
+            }
+            DisposalMethod::Previous => {
+                canvas = previous.unwrap();
+            }
+            _ => {}
+        }
+    }
+    Ok(frames)
+}
+///Encodes frames as a looping GIF. All frames must share the same dimensions.
+pub fn encode_gif(frames: &[Frame]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if let Some(first) = frames.first() {
+        let (width, height) = first.image.dimensions();
+        //the encoder writes the trailer when dropped, so keep it in its own scope
+        let mut encoder = gif::Encoder::new(&mut buf, width as u16, height as u16, &[])?;
+        encoder.set(Repeat::Infinite)?;
+        for frame in frames {
+            let mut pixels = frame.image.clone().into_raw();
+            let mut gif_frame = gif::Frame::from_rgba(width as u16, height as u16, &mut pixels);
+            gif_frame.delay = frame.delay;
+            //every frame is a whole picture, so clear the last one away instead of drawing over it,
+            //or it shows through where this one is transparent
+            gif_frame.dispose = DisposalMethod::Background;
+            encoder.write_frame(&gif_frame)?;
+        }
+    }
+    Ok(buf)
+}
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use gif;
+    use gif::DisposalMethod;
+
+    use image::{Rgba, RgbaImage};
+
+    use animation::{decode_gif, encode_gif, Frame, DEFAULT_DELAY, MAX_FRAMES};
+
+    ///A GIF frame of one solid, opaque color covering the given rect.
+    fn solid(left: u16, top: u16, width: u16, height: u16, color: [u8; 4]) -> gif::Frame<'static> {
+        let mut frame = gif::Frame::default();
+        frame.left = left;
+        frame.top = top;
+        frame.width = width;
+        frame.height = height;
+        frame.palette = Some(vec![color[0], color[1], color[2]]);
+        frame.buffer = Cow::Owned(vec![0; width as usize * height as usize]);
+        frame
+    }
+    ///Whether two pixels are the same, give or take what quantizing to a palette loses.
+    fn close(a: &Rgba<u8>, b: &Rgba<u8>) -> bool {
+        a.data.iter().zip(b.data.iter()).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 8)
+    }
+    #[test]
+    fn disposal_and_delays() {
+        let red = [255, 0, 0, 255];
+        let blue = [0, 0, 255, 255];
+        let green = [0, 255, 0, 255];
+        let mut data = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut data, 4, 4, &[]).unwrap();
+            let mut first = solid(0, 0, 4, 4, red);
+            first.delay = 5;
+            encoder.write_frame(&first).unwrap();
+            //cleared back to transparent once it's been shown
+            let mut second = solid(1, 1, 2, 2, blue);
+            second.dispose = DisposalMethod::Background;
+            encoder.write_frame(&second).unwrap();
+            encoder.write_frame(&solid(0, 0, 1, 1, green)).unwrap();
+        }
+        let frames = decode_gif(&data).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].delay, 5);
+        assert_eq!(frames[1].delay, DEFAULT_DELAY);
+        assert_eq!(frames[1].image.get_pixel(1, 1), &Rgba(blue));
+        assert_eq!(frames[1].image.get_pixel(0, 0), &Rgba(red));
+        assert_eq!(frames[2].image.get_pixel(0, 0), &Rgba(green));
+        assert_eq!(frames[2].image.get_pixel(1, 1), &Rgba([0, 0, 0, 0]));
+        assert_eq!(frames[2].image.get_pixel(3, 3), &Rgba(red));
+    }
+    #[test]
+    fn round_trip() {
+        let frames = vec![
+            Frame {
+                image: RgbaImage::from_pixel(20, 10, Rgba([255, 0, 0, 255])),
+                delay: 4,
+            },
+            Frame {
+                //the left half is see-through
+                image: RgbaImage::from_fn(20, 10, |x, _| if x < 10 {
+                    Rgba([0, 0, 0, 0])
+                } else {
+                    Rgba([0, 0, 255, 255])
+                }),
+                delay: 8,
+            },
+        ];
+        let decoded = decode_gif(&encode_gif(&frames).unwrap()).unwrap();
+        assert_eq!(decoded.len(), 2);
+        for (frame, original) in decoded.iter().zip(frames.iter()) {
+            assert_eq!(frame.delay, original.delay);
+            assert_eq!(frame.image.dimensions(), (20, 10));
+            assert!(close(frame.image.get_pixel(19, 9), original.image.get_pixel(19, 9)));
+        }
+        //the first frame doesn't show through the second one's transparent half
+        assert_eq!(decoded[1].image.get_pixel(0, 0).data[3], 0);
+    }
+    #[test]
+    fn frame_and_size_caps() {
+        let frames = (0..MAX_FRAMES + 5)
+            .map(|_| Frame {
+                image: RgbaImage::from_pixel(20, 20, Rgba([0, 0, 0, 255])),
+                delay: 1,
+            })
+            .collect::<Vec<Frame>>();
+        assert_eq!(decode_gif(&encode_gif(&frames).unwrap()).unwrap().len(), MAX_FRAMES);
+        //only the header is needed to refuse it, so there's no need for any frames
+        let mut data = Vec::new();
+        {
+            gif::Encoder::new(&mut data, 65535, 65535, &[]).unwrap();
+        }
+        assert!(decode_gif(&data).is_err());
+    }
+}
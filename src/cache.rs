@@ -0,0 +1,420 @@
+///Cache module: keeps recently downloaded images on disk, so the same picture (like a popular
+///avatar) isn't downloaded again for every meme, even after a restart. Bodies are stored in files
+///named by a hash of their contents, so an image that's behind several URLs is only kept once, and
+///an index file maps every URL to its body and the headers needed to revalidate it.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{EntityTag, HttpDate};
+
+use toml;
+
+///Name of the index file inside the cache directory.
+const INDEX_FILE: &str = "index.toml";
+
+///What the server said about a download, used to ask it whether the download has changed.
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<EntityTag>,
+    pub last_modified: Option<HttpDate>,
+}
+
+///A cached download.
+#[derive(Debug, Clone)]
+pub struct Cached {
+    pub data: Arc<Vec<u8>>,
+    pub validators: Validators,
+    ///Whether the server said the download can be reused without asking it again.
+    pub fresh: bool,
+}
+
+struct Entry {
+    hash: u64,
+    validators: Validators,
+    fresh_until: Option<SystemTime>,
+    last_used: u64,
+}
+struct Body {
+    size: u64,
+    ///How many URLs point at this body.
+    users: usize,
+}
+
+///How an entry is written in the index file.
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    #[serde(default)]
+    entries: HashMap<String, IndexEntry>,
+}
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    ///The body's hash, in hex. TOML can't hold every u64.
+    hash: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    ///Seconds since the Unix epoch.
+    fresh_until: Option<u64>,
+    last_used: u64,
+}
+
+///A size-capped cache of downloads keyed by URL, kept in a directory. When it's full, the least
+///recently used URLs are dropped first. Failing to read or write the directory never fails a
+///download, it just means that download isn't cached.
+pub struct Cache {
+    dir: PathBuf,
+    max_bytes: u64,
+    size: u64,
+    entries: HashMap<String, Entry>,
+    bodies: HashMap<u64, Body>,
+    ///Counts up on every use, to tell which entries were used least recently.
+    clock: u64,
+}
+impl Cache {
+    ///Opens the cache in `dir`, holding up to `max_bytes` of downloads. Whatever was cached there
+    ///before is picked up again. The directory is only created once something is cached, and a
+    ///size of 0 turns caching off.
+    pub fn open<P: AsRef<Path>>(dir: P, max_bytes: u64) -> Cache {
+        let mut cache = Cache {
+            dir: dir.as_ref().to_owned(),
+            max_bytes: max_bytes,
+            size: 0,
+            entries: HashMap::new(),
+            bodies: HashMap::new(),
+            clock: 0,
+        };
+        cache.load();
+        cache.evict();
+        cache.save();
+        cache
+    }
+    ///Changes the size cap, dropping entries if the cache is now too big.
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+        self.evict();
+        self.save();
+    }
+    ///The directory the cache is kept in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    ///The total size of the cached downloads on disk, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    ///Looks up the download for `url`, marking it as recently used. Only touches the index on disk
+    ///if the download turned out to be missing.
+    pub fn get(&mut self, url: &str) -> Option<Cached> {
+        self.clock += 1;
+        let clock = self.clock;
+        let read = match self.entries.get_mut(url) {
+            Some(entry) => {
+                entry.last_used = clock;
+                read_file(&body_path(&self.dir, entry.hash)).map(|data| {
+                    Cached {
+                        data: Arc::new(data),
+                        validators: entry.validators.clone(),
+                        fresh: entry.fresh_until.map_or(false, |x| SystemTime::now() < x),
+                    }
+                })
+            }
+            None => return None,
+        };
+        if read.is_none() {
+            //the file went missing or can't be read, so the entry is no use any more
+            self.forget(url);
+            self.save();
+        }
+        //last_used only matters for eviction, so it's written along with the next change instead
+        //of on every hit
+        read
+    }
+    ///Stores the download for `url`, replacing whatever was there. `max_age` is how long it can be
+    ///reused without asking the server again.
+    pub fn insert(
+        &mut self,
+        url: &str,
+        data: Arc<Vec<u8>>,
+        validators: Validators,
+        max_age: Option<Duration>,
+    ) {
+        self.forget(url);
+        if data.len() as u64 > self.max_bytes || fs::create_dir_all(&self.dir).is_err() {
+            self.save();
+            return;
+        }
+        let hash = hash(data.as_slice());
+        let path = body_path(&self.dir, hash);
+        if self.bodies.contains_key(&hash) {
+            //a hash collision between two different bodies. Just don't cache the new one
+            if read_file(&path).map_or(true, |x| x != *data) {
+                self.save();
+                return;
+            }
+        } else if write_file(&path, data.as_slice()).is_err() {
+            let _ = fs::remove_file(&path);
+            self.save();
+            return;
+        }
+        let size = data.len() as u64;
+        let shared = {
+            let body = self.bodies.entry(hash).or_insert_with(|| Body {
+                size: size,
+                users: 0,
+            });
+            body.users += 1;
+            body.users > 1
+        };
+        if !shared {
+            self.size += size;
+        }
+        self.clock += 1;
+        self.entries.insert(
+            url.to_owned(),
+            Entry {
+                hash: hash,
+                validators: validators,
+                fresh_until: max_age.map(|x| SystemTime::now() + x),
+                last_used: self.clock,
+            },
+        );
+        self.evict();
+        self.save();
+    }
+    ///Marks the download for `url` as still good, after the server said it hasn't changed.
+    pub fn refresh(&mut self, url: &str, max_age: Option<Duration>) {
+        if let Some(entry) = self.entries.get_mut(url) {
+            entry.fresh_until = max_age.map(|x| SystemTime::now() + x);
+        }
+        self.save();
+    }
+    ///Forgets the download for `url`, deleting its body if no other URL uses it.
+    pub fn remove(&mut self, url: &str) {
+        self.forget(url);
+        self.save();
+    }
+    fn forget(&mut self, url: &str) {
+        let entry = match self.entries.remove(url) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let unused = {
+            let body = self.bodies.get_mut(&entry.hash).unwrap();
+            body.users -= 1;
+            body.users == 0
+        };
+        if unused {
+            let body = self.bodies.remove(&entry.hash).unwrap();
+            self.size -= body.size;
+            let _ = fs::remove_file(body_path(&self.dir, entry.hash));
+        }
+    }
+    ///Drops the least recently used entries until the cache fits in its cap.
+    fn evict(&mut self) {
+        while self.size > self.max_bytes {
+            let oldest = match self.entries.iter().min_by_key(|&(_, entry)| entry.last_used) {
+                Some((url, _)) => url.clone(),
+                None => break,
+            };
+            self.forget(oldest.as_str());
+        }
+    }
+    ///Reads the index, skipping entries whose bodies have gone missing.
+    fn load(&mut self) {
+        let index = read_file(&self.dir.join(INDEX_FILE))
+            .and_then(|x| String::from_utf8(x).ok())
+            .and_then(|x| toml::from_str::<Index>(x.as_str()).ok())
+            .unwrap_or_default();
+        for (url, entry) in index.entries {
+            let hash = match u64::from_str_radix(entry.hash.as_str(), 16) {
+                Ok(hash) => hash,
+                Err(_) => continue,
+            };
+            let size = match fs::metadata(body_path(&self.dir, hash)) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            let shared = {
+                let body = self.bodies.entry(hash).or_insert_with(|| Body {
+                    size: size,
+                    users: 0,
+                });
+                body.users += 1;
+                body.users > 1
+            };
+            if !shared {
+                self.size += size;
+            }
+            self.clock = self.clock.max(entry.last_used);
+            self.entries.insert(
+                url,
+                Entry {
+                    hash: hash,
+                    validators: Validators {
+                        etag: entry.etag.and_then(|x| x.parse().ok()),
+                        last_modified: entry.last_modified.and_then(|x| x.parse().ok()),
+                    },
+                    fresh_until: entry.fresh_until.map(|x| UNIX_EPOCH + Duration::from_secs(x)),
+                    last_used: entry.last_used,
+                },
+            );
+        }
+    }
+    ///Writes the index, replacing the old one all at once so a crash can't leave half of it.
+    fn save(&self) {
+        if !self.dir.exists() {
+            return; //nothing has ever been cached here
+        }
+        let index = Index {
+            entries: self.entries
+                .iter()
+                .map(|(url, entry)| {
+                    (
+                        url.clone(),
+                        IndexEntry {
+                            hash: format!("{:016x}", entry.hash),
+                            etag: entry.validators.etag.as_ref().map(|x| x.to_string()),
+                            last_modified: entry.validators.last_modified.map(|x| x.to_string()),
+                            fresh_until: entry.fresh_until.and_then(|x| {
+                                x.duration_since(UNIX_EPOCH).ok().map(|x| x.as_secs())
+                            }),
+                            last_used: entry.last_used,
+                        },
+                    )
+                })
+                .collect(),
+        };
+        if let Ok(text) = toml::to_string(&index) {
+            let temp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+            if write_file(&temp, text.as_bytes()).is_ok() {
+                let _ = fs::rename(&temp, self.dir.join(INDEX_FILE));
+            }
+        }
+    }
+}
+fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+///Where the body with the given hash is kept.
+fn body_path(dir: &Path, hash: u64) -> PathBuf {
+    dir.join(format!("{:016x}", hash))
+}
+fn read_file(path: &Path) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+        Ok(_) => Some(data),
+        Err(_) => None,
+    }
+}
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    File::create(path)?.write_all(data)
+}
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::header::EntityTag;
+
+    use cache::{read_file, Cache, Validators, INDEX_FILE};
+
+    fn body(byte: u8, len: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![byte; len])
+    }
+    ///An empty directory for one test's cache.
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("meme_bot_cache_test_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+    fn tagged() -> Validators {
+        Validators {
+            etag: Some(EntityTag::strong("avatar".to_owned())),
+            last_modified: None,
+        }
+    }
+    ///How many bodies are stored in `dir`.
+    fn body_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|x| !x.as_ref().unwrap().file_name().to_string_lossy().contains("index"))
+            .count()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = cache_dir("evicts");
+        let mut cache = Cache::open(&dir, 30);
+        cache.insert("a", body(1, 10), Validators::default(), None);
+        cache.insert("b", body(2, 10), Validators::default(), None);
+        cache.insert("c", body(3, 10), Validators::default(), None);
+        //using a makes b the oldest
+        assert!(cache.get("a").is_some());
+        cache.insert("d", body(4, 10), Validators::default(), None);
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some() && cache.get("c").is_some() && cache.get("d").is_some());
+        assert_eq!(cache.size(), 30);
+        assert_eq!(body_files(&dir), 3);
+        //too big to ever fit
+        cache.insert("e", body(5, 31), Validators::default(), None);
+        assert!(cache.get("e").is_none());
+        cache.set_max_bytes(0);
+        assert_eq!(cache.size(), 0);
+        assert_eq!(body_files(&dir), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn shares_identical_bodies() {
+        let dir = cache_dir("shares");
+        let mut cache = Cache::open(&dir, 100);
+        cache.insert("a", body(1, 40), Validators::default(), None);
+        cache.insert("b", body(1, 40), Validators::default(), None);
+        assert_eq!(cache.size(), 40);
+        assert_eq!(body_files(&dir), 1);
+        cache.remove("a");
+        assert_eq!(cache.size(), 40);
+        assert_eq!(cache.get("b").unwrap().data.len(), 40);
+        cache.remove("b");
+        assert_eq!(cache.size(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn survives_reopening() {
+        let dir = cache_dir("reopens");
+        {
+            let mut cache = Cache::open(&dir, 100);
+            cache.insert("a", body(1, 40), tagged(), Some(Duration::from_secs(60)));
+            cache.insert("b", body(2, 40), Validators::default(), None);
+        }
+        //reopening with a smaller cap drops the least recently used download from disk
+        let mut cache = Cache::open(&dir, 50);
+        assert_eq!(cache.size(), 40);
+        assert_eq!(body_files(&dir), 1);
+        assert!(cache.get("a").is_none());
+        let cached = cache.get("b").unwrap();
+        assert_eq!(*cached.data, vec![2; 40]);
+        assert!(!cached.fresh);
+        cache.insert("a", body(1, 10), tagged(), Some(Duration::from_secs(60)));
+        //hits don't rewrite the index
+        let index = read_file(&dir.join(INDEX_FILE)).unwrap();
+        assert!(cache.get("b").is_some());
+        assert_eq!(read_file(&dir.join(INDEX_FILE)).unwrap(), index);
+        let cached = Cache::open(&dir, 50).get("a").unwrap();
+        assert!(cached.fresh);
+        assert_eq!(cached.validators.etag, Some(EntityTag::strong("avatar".to_owned())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
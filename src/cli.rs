@@ -0,0 +1,118 @@
+///CLI module: renders templates straight to a file, without logging into Discord.
+///Useful for previewing templates while authoring them.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use meme_bot::parse;
+use meme_bot::template::{get_template, load_templates, OutputFormat, Rendered, RenderOptions};
+
+pub const USAGE: &str = "Usage: meme_bot render <template> [\"text1\" \"text2\" ...] [-o <output.png|.jpg|.gif>] [--rectangles] [render options like --deepfry]";
+
+///Runs the `render` subcommand. `args` are the command-line arguments following `render`.
+pub fn render(args: &[String]) -> Result<(), String> {
+    let mut template_name = None;
+    let mut texts = Vec::new();
+    let mut output = "meme.png".to_owned();
+    let mut show_rectangles = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                output = args.next()
+                    .ok_or_else(|| format!("Missing path after {}", arg))?
+                    .clone();
+            }
+            "-r" | "--rectangles" => {
+                show_rectangles = true;
+            }
+            "-h" | "--help" => {
+                return Err(USAGE.to_owned());
+            }
+            _ => {
+                if template_name.is_none() {
+                    template_name = Some(arg.clone());
+                } else if arg.starts_with("--") {
+                    texts.push(arg.clone()); //a render option, like --deepfry
+                } else {
+                    texts.push(quote_arg(arg));
+                }
+            }
+        }
+    }
+    let template_name = template_name.ok_or_else(|| USAGE.to_owned())?;
+
+    let templates = load_templates("./templates").map_err(|(template, e)| if let Some(filename) = template {
+        format!("Error loading template {}: {}", filename, e)
+    } else {
+        format!("Error loading templates: {}", e)
+    })?;
+    let template = get_template(&templates, template_name.as_str()).ok_or_else(|| {
+        format!(
+            "{} is not a valid template. Options: {}",
+            template_name,
+            templates
+                .iter()
+                .map(|x| x.short_name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )
+    })?;
+
+    //the output file decides the format, whatever the render options say
+    let format = match Path::new(&output).extension().and_then(|e| e.to_str()) {
+        Some("gif") => OutputFormat::Gif,
+        Some("jpg") | Some("jpeg") => OutputFormat::Jpeg,
+        Some("png") | None => OutputFormat::Png,
+        Some(other) => return Err(format!("Unsupported output format: {}", other)),
+    };
+
+    //run the arguments through the same parser as the meme command, so escapes behave the same
+    let (texts, mut options) = if texts.is_empty() {
+        (Vec::new(), RenderOptions::default()) //render the bare template
+    } else {
+        let texts = texts.iter().map(|x| x.as_str()).collect::<Vec<&str>>();
+        let arguments = parse::parse_arguments(texts.as_slice()).map_err(|e| {
+            format!("Error parsing your input: {}\n{}", e, e.caret(texts.join(" ").as_str()))
+        })?;
+        let texts = template.arrange(&arguments).map_err(|e| {
+            format!("Error parsing your input: {}", e)
+        })?;
+        (texts, arguments.options)
+    };
+    options.show_rectangles = show_rectangles;
+    options.format = Some(format);
+    let meme = template
+        .render(
+            texts.iter().map(|x| x.as_str()).collect::<Vec<&str>>().as_slice(),
+            &options,
+        )
+        .map_err(|e| format!("Error rendering: {}", e))?;
+
+    if let Rendered::Animated(_) = meme {
+        if format != OutputFormat::Gif {
+            warn!("{} is animated, only saving the first frame", template.short_name);
+        }
+    }
+    let (buf, _) = meme.encode_as(format).map_err(|e| {
+        format!("Error encoding {}: {}", output, e)
+    })?;
+    File::create(&output)
+        .and_then(|mut file| file.write_all(buf.as_slice()))
+        .map_err(|e| format!("Error saving {}: {}", output, e))?;
+    info!("Rendered {} to {}", template.short_name, output);
+    Ok(())
+}
+///Wraps a shell argument in double quotes so the parser keeps it as a single text. An `id=` in
+///front is left outside the quotes, so the argument still goes to that feature.
+fn quote_arg(arg: &str) -> String {
+    let quote = |text: &str| format!("\"{}\"", text.replace('"', "\\\""));
+    match arg.find('=') {
+        Some(index) if index > 0 &&
+                       arg[..index].chars().all(|c| c.is_alphanumeric() || c == '_') => {
+            format!("{}={}", &arg[..index], quote(&arg[index + 1..]))
+        }
+        _ => quote(arg),
+    }
+}
@@ -11,6 +11,8 @@ use std::path::Path;
 
 use toml;
 
+use meme_bot::fetch::FetchConfig;
+
 use self::defaults::*;
 
 mod defaults {
@@ -26,6 +28,9 @@ pub struct Config {
     ///The bot's prefix. Default is '+'
     #[serde(default = "default_prefix")]
     pub prefixes: Vec<String>,
+    ///Limits on downloading images for image features.
+    #[serde(default)]
+    pub fetch: FetchConfig,
 }
 impl Config {
     pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
@@ -46,6 +51,7 @@ impl Config {
         Config {
             token: "".to_owned(),
             prefixes: Vec::new(),
+            fetch: FetchConfig::default(),
         }
     }
 }
@@ -0,0 +1,129 @@
+///Crop module: picks which part of an image to keep when it's cropped to fit a feature, by looking
+///for the busiest (most edges) and most skin-coloured part of the picture.
+
+use std::cmp::min;
+
+use image::imageops::resize;
+use image::{FilterType, RgbaImage};
+
+///Longest side of the thumbnail the heuristic runs on. Bigger is slower but more precise.
+const THUMBNAIL_SIZE: u32 = 64;
+///Extra energy given to skin-coloured pixels, so faces win over busy backgrounds.
+const SKIN_WEIGHT: f32 = 60.0;
+
+///Finds where the `window`-sized crop should start within `image` once it's resized to `size`.
+///Returns the crop's top-left corner, in pixels of the resized image.
+pub fn smart_window(image: &RgbaImage, size: (u32, u32), window: (u32, u32)) -> (u32, u32) {
+    let window = (min(window.0, size.0), min(window.1, size.1));
+    if window == size {
+        return (0, 0); //nothing gets cropped
+    }
+    //work on a thumbnail, since only the rough position matters
+    let scale = (THUMBNAIL_SIZE as f32 / size.0.max(size.1) as f32).min(1.0);
+    let thumb_size = (
+        ((size.0 as f32 * scale).round() as u32).max(1),
+        ((size.1 as f32 * scale).round() as u32).max(1),
+    );
+    let thumb = resize(image, thumb_size.0, thumb_size.1, FilterType::Triangle);
+    let table = summed_area(&energy(&thumb), thumb_size.0 as usize, thumb_size.1 as usize);
+    let thumb_window = (
+        min(((window.0 as f32 * scale).round() as u32).max(1), thumb_size.0) as usize,
+        min(((window.1 as f32 * scale).round() as u32).max(1), thumb_size.1) as usize,
+    );
+
+    //slide the window over every position, keeping the one with the most energy. Ties go to the
+    //position closest to the middle.
+    let (free_x, free_y) = (
+        thumb_size.0 as usize - thumb_window.0,
+        thumb_size.1 as usize - thumb_window.1,
+    );
+    let mut best = (free_x / 2, free_y / 2);
+    let mut best_energy = window_sum(&table, thumb_size.0 as usize, best, thumb_window);
+    for x in 0..free_x + 1 {
+        for y in 0..free_y + 1 {
+            let sum = window_sum(&table, thumb_size.0 as usize, (x, y), thumb_window);
+            let closer = distance((x, y), (free_x / 2, free_y / 2)) <
+                distance(best, (free_x / 2, free_y / 2));
+            if sum > best_energy || (sum == best_energy && closer) {
+                best = (x, y);
+                best_energy = sum;
+            }
+        }
+    }
+    (
+        min((best.0 as f32 / scale).round() as u32, size.0 - window.0),
+        min((best.1 as f32 / scale).round() as u32, size.1 - window.1),
+    )
+}
+///How "interesting" each pixel is: its edge strength plus a bonus for skin tones.
+fn energy(image: &RgbaImage) -> Vec<f32> {
+    let (width, height) = image.dimensions();
+    let luma = |x: u32, y: u32| {
+        let p = image.get_pixel(x, y).data;
+        (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) * p[3] as f32 / 255.0
+    };
+    let mut energy = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let horizontal = luma(min(x + 1, width - 1), y) - luma(x.saturating_sub(1), y);
+            let vertical = luma(x, min(y + 1, height - 1)) - luma(x, y.saturating_sub(1));
+            let mut value = horizontal.abs() + vertical.abs();
+            if is_skin(image.get_pixel(x, y).data) {
+                value += SKIN_WEIGHT;
+            }
+            energy.push(value);
+        }
+    }
+    energy
+}
+///A rough RGB skin-tone test.
+fn is_skin(p: [u8; 4]) -> bool {
+    let (r, g, b) = (p[0] as i32, p[1] as i32, p[2] as i32);
+    p[3] > 128 && r > 95 && g > 40 && b > 20 && r > g && r > b && (r - g).abs() > 15 &&
+        r.max(g).max(b) - r.min(g).min(b) > 15
+}
+///Builds a summed-area table, with an extra row and column of zeroes at the top and left.
+fn summed_area(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut table = vec![0.0; (width + 1) * (height + 1)];
+    for y in 0..height {
+        for x in 0..width {
+            table[(y + 1) * (width + 1) + x + 1] = values[y * width + x] +
+                table[y * (width + 1) + x + 1] +
+                table[(y + 1) * (width + 1) + x] -
+                table[y * (width + 1) + x];
+        }
+    }
+    table
+}
+fn window_sum(table: &[f32], width: usize, at: (usize, usize), size: (usize, usize)) -> f32 {
+    let stride = width + 1;
+    let (x0, y0, x1, y1) = (at.0, at.1, at.0 + size.0, at.1 + size.1);
+    table[y1 * stride + x1] - table[y0 * stride + x1] - table[y1 * stride + x0] +
+        table[y0 * stride + x0]
+}
+fn distance(a: (usize, usize), b: (usize, usize)) -> usize {
+    (a.0 as isize - b.0 as isize).abs() as usize + (a.1 as isize - b.1 as isize).abs() as usize
+}
+#[cfg(test)]
+mod test {
+    use image::{Rgba, RgbaImage};
+
+    use crop::smart_window;
+
+    #[test]
+    fn finds_the_busy_part() {
+        //a flat grey image with a checkerboard patch on the right
+        let mut image = RgbaImage::from_pixel(200, 100, Rgba([128, 128, 128, 255]));
+        for x in 150..190 {
+            for y in 30..70 {
+                let shade = if (x / 16 + y / 16) % 2 == 0 { 0 } else { 255 };
+                image.put_pixel(x, y, Rgba([shade, shade, shade, 255]));
+            }
+        }
+        let (x, y) = smart_window(&image, (200, 100), (100, 100));
+        assert_eq!(y, 0);
+        assert!(x >= 90, "window starts at {}", x);
+        //nothing to crop
+        assert_eq!(smart_window(&image, (200, 100), (200, 100)), (0, 0));
+    }
+}
@@ -0,0 +1,228 @@
+///Emoji module: finds emoji in text and loads their pictures from the emoji set, a folder of PNGs
+///named after their codepoints the way Twemoji names them (like `1f600.png` or
+///`1f468-200d-1f4bb.png`).
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use image;
+use image::RgbaImage;
+
+use template::Result;
+
+///Where the emoji set lives.
+pub const EMOJI_DIR: &str = "./emoji";
+///The most characters a single emoji can be made of (long ZWJ sequences, like families).
+const MAX_SEQUENCE: usize = 10;
+const ZERO_WIDTH_JOINER: char = '\u{200d}';
+const VARIATION_SELECTOR: char = '\u{fe0f}';
+const KEYCAP: char = '\u{20e3}';
+///Ranges of codepoints (inclusive) that emoji start with, outside of keycaps like #️⃣.
+const PICTOGRAPHIC: &[(u32, u32)] = &[
+    (0xa9, 0xa9),
+    (0xae, 0xae),
+    (0x203c, 0x203c),
+    (0x2049, 0x2049),
+    (0x2122, 0x2122),
+    (0x2139, 0x2139),
+    (0x2190, 0x21ff),
+    (0x2300, 0x23ff),
+    (0x24c2, 0x24c2),
+    (0x25a0, 0x27bf),
+    (0x2900, 0x297f),
+    (0x2b00, 0x2bff),
+    (0x3030, 0x3030),
+    (0x303d, 0x303d),
+    (0x3297, 0x3297),
+    (0x3299, 0x3299),
+    (0x1f000, 0x1faff),
+];
+
+lazy_static! {
+    ///The names of every emoji in the set, read on first use.
+    static ref AVAILABLE: HashSet<String> = list(EMOJI_DIR);
+    static ref IMAGES: RwLock<HashMap<String, Arc<RgbaImage>>> = RwLock::new(HashMap::new());
+}
+
+///A piece of text: either plain text, or a single emoji.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Piece<'a> {
+    Text(&'a str),
+    Emoji(&'a str),
+}
+
+///The file name (without extension) of `emoji`'s picture: its codepoints in hex, joined by dashes.
+///Like Twemoji, variation selectors are left out unless the emoji is a ZWJ sequence.
+pub fn file_name(emoji: &str) -> String {
+    let keep_selectors = emoji.contains(ZERO_WIDTH_JOINER);
+    emoji
+        .chars()
+        .filter(|&c| keep_selectors || c != VARIATION_SELECTOR)
+        .map(|c| format!("{:x}", c as u32))
+        .collect::<Vec<String>>()
+        .join("-")
+}
+///Whether `text` is exactly one emoji that's in the emoji set.
+pub fn is_emoji(text: &str) -> bool {
+    let text = text.trim();
+    !text.is_empty() && find(&AVAILABLE, text) == Some(text.len())
+}
+///Whether `text` looks like a single emoji, whether or not it's in the emoji set. Used to tell an
+///emoji without a picture apart from ordinary text.
+pub fn looks_like_emoji(text: &str) -> bool {
+    let text = text.trim();
+    let mut chars = text.chars();
+    let first = match chars.next() {
+        Some(first) => first,
+        None => return false,
+    };
+    let keycap = text.ends_with(KEYCAP) && "0123456789#*".contains(first);
+    text.chars().count() <= MAX_SEQUENCE && (keycap || is_pictographic(first)) &&
+        chars.all(|c| {
+            //skin tones are pictographic already, tags are for flags like England's
+            is_pictographic(c) || c == ZERO_WIDTH_JOINER || c == VARIATION_SELECTOR || c == KEYCAP ||
+                (c >= '\u{e0020}' && c <= '\u{e007f}')
+        })
+}
+///Whether `c` is in one of the blocks emoji are drawn from.
+fn is_pictographic(c: char) -> bool {
+    PICTOGRAPHIC
+        .iter()
+        .any(|&(start, end)| c as u32 >= start && c as u32 <= end)
+}
+///Splits `text` into plain text and the emoji in the emoji set.
+pub fn split(text: &str) -> Vec<Piece> {
+    split_with(&AVAILABLE, text)
+}
+///How many emoji the set on disk has pictures for. 0 means the set hasn't been installed.
+pub fn available() -> usize {
+    AVAILABLE.len()
+}
+///The raw PNG data of `emoji`'s picture.
+pub fn data(emoji: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path(emoji.trim()))?.read_to_end(&mut data)?;
+    Ok(data)
+}
+///The decoded picture of `emoji`, loaded once and shared after that.
+pub fn image(emoji: &str) -> Result<Arc<RgbaImage>> {
+    let name = file_name(emoji);
+    if let Some(image) = IMAGES.read().unwrap().get(&name) {
+        return Ok(image.clone());
+    }
+    let image = Arc::new(image::load_from_memory(data(emoji)?.as_slice())?.to_rgba());
+    IMAGES.write().unwrap().insert(name, image.clone());
+    Ok(image)
+}
+fn path(emoji: &str) -> String {
+    format!("{}/{}.png", EMOJI_DIR, file_name(emoji))
+}
+///Reads the names of the emoji in `dir`. A missing folder just means no emoji.
+fn list<P: AsRef<Path>>(dir: P) -> HashSet<String> {
+    let mut names = HashSet::new();
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(|x| x.ok()) {
+            let path = entry.path();
+            if path.extension().map_or(false, |x| x == "png") {
+                if let Some(name) = path.file_stem().and_then(|x| x.to_str()) {
+                    names.insert(name.to_lowercase());
+                }
+            }
+        }
+    }
+    names
+}
+///The length in bytes of the longest emoji in `available` at the start of `text`, if there is one.
+fn find(available: &HashSet<String>, text: &str) -> Option<usize> {
+    let mut chars = text.chars();
+    let first = match chars.next() {
+        Some(first) => first,
+        None => return None,
+    };
+    //plain ASCII only starts an emoji as a keycap, like #️⃣
+    let keycap = chars.next().map_or(false, |c| c == VARIATION_SELECTOR || c == KEYCAP);
+    if (first as u32) < 0x80 && !keycap {
+        return None;
+    }
+    let ends = text.char_indices()
+        .map(|(index, c)| index + c.len_utf8())
+        .take(MAX_SEQUENCE)
+        .collect::<Vec<usize>>();
+    ends.into_iter()
+        .rev()
+        .find(|&end| available.contains(&file_name(&text[..end])))
+}
+fn split_with<'a>(available: &HashSet<String>, text: &'a str) -> Vec<Piece<'a>> {
+    let mut pieces = Vec::new();
+    let mut text_start = 0;
+    let mut index = 0;
+    while index < text.len() {
+        if let Some(length) = find(available, &text[index..]) {
+            if text_start < index {
+                pieces.push(Piece::Text(&text[text_start..index]));
+            }
+            pieces.push(Piece::Emoji(&text[index..index + length]));
+            index += length;
+            text_start = index;
+        } else {
+            index += text[index..].chars().next().unwrap().len_utf8();
+        }
+    }
+    if text_start < text.len() {
+        pieces.push(Piece::Text(&text[text_start..]));
+    }
+    pieces
+}
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use emoji::{file_name, looks_like_emoji, split_with, Piece};
+
+    #[test]
+    fn names_like_twemoji() {
+        assert_eq!(file_name("😀"), "1f600");
+        assert_eq!(file_name("❤\u{fe0f}"), "2764");
+        assert_eq!(file_name("#\u{fe0f}\u{20e3}"), "23-20e3");
+        assert_eq!(file_name("👨\u{200d}💻"), "1f468-200d-1f4bb");
+    }
+    #[test]
+    fn splits_out_emoji() {
+        let available = ["1f600", "2764", "1f468", "1f468-200d-1f4bb", "1f44d", "1f44d-1f3fd"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<HashSet<String>>();
+        assert_eq!(
+            split_with(&available, "i ❤\u{fe0f} rust 😀"),
+            vec![
+                Piece::Text("i "),
+                Piece::Emoji("❤\u{fe0f}"),
+                Piece::Text(" rust "),
+                Piece::Emoji("😀"),
+            ]
+        );
+        //the longest sequence wins
+        assert_eq!(
+            split_with(&available, "👨\u{200d}💻👍🏽"),
+            vec![Piece::Emoji("👨\u{200d}💻"), Piece::Emoji("👍🏽")]
+        );
+        //emoji that aren't in the set stay text
+        assert_eq!(split_with(&available, "é 🦀"), vec![Piece::Text("é 🦀")]);
+    }
+    #[test]
+    fn recognizes_emoji_outside_the_set() {
+        assert!(looks_like_emoji("🦀"));
+        assert!(looks_like_emoji(" 👍🏽 "));
+        assert!(looks_like_emoji("👨\u{200d}💻"));
+        assert!(looks_like_emoji("#\u{fe0f}\u{20e3}"));
+        assert!(looks_like_emoji("🇫🇷"));
+        assert!(!looks_like_emoji("lava"));
+        assert!(!looks_like_emoji("é"));
+        assert!(!looks_like_emoji("🦀 crab"));
+        assert!(!looks_like_emoji(""));
+    }
+}
@@ -0,0 +1,883 @@
+///Fetch module: downloads the images used by image features, with limits on how long it can take,
+///how big the download can be, and what it has to contain. Downloads are cached, see the cache
+///module.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::result;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper;
+use hyper::client::{Client, RedirectPolicy};
+use hyper::header::{CacheControl, CacheDirective, ContentLength, ContentType, ETag, Headers,
+                    IfModifiedSince, IfNoneMatch, LastModified, Location};
+use hyper::mime::{Mime, TopLevel};
+use hyper::net::{HttpStream, HttpsConnector, NetworkConnector};
+use hyper::status::StatusCode;
+use hyper_native_tls::NativeTlsClient;
+
+use url::Url;
+
+use image;
+
+use cache::{Cache, Validators};
+
+use self::defaults::*;
+
+mod defaults {
+    use std::path::PathBuf;
+
+    pub fn default_connect_timeout() -> u64 {
+        5
+    }
+    pub fn default_read_timeout() -> u64 {
+        10
+    }
+    pub fn default_max_bytes() -> u64 {
+        8 * 1024 * 1024
+    }
+    pub fn default_max_redirects() -> u32 {
+        5
+    }
+    pub fn default_render_timeout() -> u64 {
+        15
+    }
+    pub fn default_cache_size() -> u64 {
+        64 * 1024 * 1024
+    }
+    pub fn default_cache_dir() -> PathBuf {
+        PathBuf::from("./cache")
+    }
+    pub fn default_allowed_schemes() -> Vec<String> {
+        vec!["http".to_owned(), "https".to_owned()]
+    }
+    pub fn default_blocked_networks() -> Vec<String> {
+        [
+            "0.0.0.0/8",
+            "10.0.0.0/8",
+            "100.64.0.0/10",
+            "127.0.0.0/8",
+            "169.254.0.0/16",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "224.0.0.0/4",
+            "255.255.255.255/32",
+            //IPv4-compatible, NAT64 and 6to4 addresses can all lead back into the ranges above
+            "::/96",
+            "::1/128",
+            "64:ff9b::/96",
+            "2002::/16",
+            "fc00::/7",
+            "fe80::/10",
+            "ff00::/8",
+        ].iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+}
+
+///Limits on image downloads. Can be loaded as part of the bot's config.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchConfig {
+    ///How long to wait for a connection, in seconds.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    ///How long to wait for data once connected, in seconds.
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: u64,
+    ///The largest download allowed, in bytes.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    ///How many redirects to follow before giving up.
+    #[serde(default = "default_max_redirects")]
+    pub max_redirects: u32,
+    ///How long all the downloads for one meme may take together, in seconds.
+    #[serde(default = "default_render_timeout")]
+    pub render_timeout: u64,
+    ///How many bytes of downloads to keep around for reuse. 0 turns the cache off.
+    #[serde(default = "default_cache_size")]
+    pub cache_size: u64,
+    ///Where downloads are kept for reuse. Created the first time something is cached.
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: PathBuf,
+    ///URL schemes that may be downloaded from.
+    #[serde(default = "default_allowed_schemes")]
+    pub allowed_schemes: Vec<String>,
+    ///Networks (in CIDR notation) that may never be connected to. Checked against the addresses
+    ///a host resolves to, so DNS can't be used to sneak around it.
+    #[serde(default = "default_blocked_networks")]
+    pub blocked_networks: Vec<String>,
+    ///If set, only these domains (and their subdomains) may be downloaded from.
+    pub allowed_domains: Option<Vec<String>>,
+}
+impl Default for FetchConfig {
+    fn default() -> FetchConfig {
+        FetchConfig {
+            connect_timeout: default_connect_timeout(),
+            read_timeout: default_read_timeout(),
+            max_bytes: default_max_bytes(),
+            max_redirects: default_max_redirects(),
+            render_timeout: default_render_timeout(),
+            cache_size: default_cache_size(),
+            cache_dir: default_cache_dir(),
+            allowed_schemes: default_allowed_schemes(),
+            blocked_networks: default_blocked_networks(),
+            allowed_domains: None,
+        }
+    }
+}
+impl FetchConfig {
+    ///Parses `blocked_networks`.
+    fn networks(&self) -> result::Result<Vec<Network>, String> {
+        self.blocked_networks
+            .iter()
+            .map(|x| Network::parse(x.as_str()))
+            .collect()
+    }
+    ///Checks that `url` may be downloaded from, resolving its host to make sure it doesn't point
+    ///into a blocked network.
+    fn check(&self, url: &Url, networks: &[Network]) -> result::Result<(), FetchError> {
+        if !self.allowed_schemes.iter().any(|x| x == url.scheme()) {
+            return Err(FetchError::Blocked(
+                format!("{} links aren't allowed", url.scheme()),
+            ));
+        }
+        let host = match url.host_str() {
+            Some(host) => host.trim_matches(|c| c == '[' || c == ']').to_lowercase(),
+            None => return Err(FetchError::Blocked("the link has no host".to_owned())),
+        };
+        if let Some(ref domains) = self.allowed_domains {
+            let allowed = domains.iter().any(|domain| {
+                let domain = domain.to_lowercase();
+                host == domain || host.ends_with(format!(".{}", domain).as_str())
+            });
+            if !allowed {
+                return Err(FetchError::Blocked(format!("{} isn't an allowed site", host)));
+            }
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs = (host.as_str(), port).to_socket_addrs()?.collect::<Vec<SocketAddr>>();
+        if !addrs.is_empty() && addrs.iter().all(|addr| is_blocked(addr.ip(), networks)) {
+            return Err(FetchError::Blocked(
+                format!("{} is on a private network", host),
+            ));
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: RwLock<FetchConfig> = RwLock::new(FetchConfig::default());
+    static ref CACHE: Arc<Mutex<Cache>> =
+        Arc::new(Mutex::new(Cache::open(default_cache_dir(), default_cache_size())));
+}
+
+///Sets the limits used by every download from now on. Fails if any of the blocked networks can't
+///be parsed.
+pub fn configure(config: FetchConfig) -> result::Result<(), String> {
+    config.networks()?;
+    {
+        let mut cache = CACHE.lock().unwrap();
+        if cache.dir() == config.cache_dir.as_path() {
+            cache.set_max_bytes(config.cache_size);
+        } else {
+            *cache = Cache::open(&config.cache_dir, config.cache_size);
+        }
+    }
+    *CONFIG.write().unwrap() = config;
+    Ok(())
+}
+
+///The URL schemes images may be downloaded from, as configured.
+pub fn allowed_schemes() -> Vec<String> {
+    CONFIG.read().unwrap().allowed_schemes.clone()
+}
+///Downloads the image at `url`, following redirects. Fails if the download breaks any of the
+///configured limits or isn't an image. Cached downloads are reused if the server says they're
+///still good.
+pub fn fetch(url: &Url) -> result::Result<Arc<Vec<u8>>, FetchError> {
+    let config = CONFIG.read().unwrap().clone();
+    let deadline = Instant::now() + Duration::from_secs(config.render_timeout);
+    fetch_with(url, &config, &CACHE, deadline)
+}
+///Downloads all of `urls` at the same time, each like `fetch`. Downloads that haven't finished
+///once the configured render timeout runs out fail with FetchError::TimedOut. The results are in
+///the same order as `urls`.
+pub fn fetch_all(urls: &[Url]) -> Vec<result::Result<Arc<Vec<u8>>, FetchError>> {
+    let config = CONFIG.read().unwrap().clone();
+    fetch_all_with(urls, &config, &CACHE)
+}
+fn fetch_all_with(
+    urls: &[Url],
+    config: &FetchConfig,
+    cache: &Arc<Mutex<Cache>>,
+) -> Vec<result::Result<Arc<Vec<u8>>, FetchError>> {
+    //the same link twice (like two mentions of one user) is only downloaded once
+    let mut unique: Vec<&Url> = Vec::new();
+    for url in urls {
+        if !unique.contains(&url) {
+            unique.push(url);
+        }
+    }
+    let (sender, receiver) = channel();
+    let deadline = Instant::now() + Duration::from_secs(config.render_timeout);
+    for (index, url) in unique.iter().enumerate() {
+        let (sender, url) = (sender.clone(), (*url).clone());
+        let (config, cache) = (config.clone(), cache.clone());
+        thread::spawn(move || {
+            let _ = sender.send((index, fetch_with(&url, &config, &cache, deadline)));
+        });
+    }
+    //wait for the downloads until time runs out. Any still running give up on their own once
+    //they notice the deadline has passed
+    let mut results = unique.iter().map(|_| None).collect::<Vec<_>>();
+    for _ in 0..unique.len() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match receiver.recv_timeout(deadline - now) {
+            Ok((index, result)) => results[index] = Some(result),
+            Err(_) => break,
+        }
+    }
+    urls.iter()
+        .map(|url| {
+            let index = unique.iter().position(|x| *x == url).unwrap();
+            match results[index] {
+                Some(Ok(ref data)) => Ok(data.clone()),
+                Some(Err(ref e)) => Err(e.duplicate()),
+                None => Err(FetchError::TimedOut(config.render_timeout)),
+            }
+        })
+        .collect()
+}
+///Downloads `url` like `fetch`, giving up with FetchError::TimedOut once `deadline` passes, even
+///in the middle of a download.
+fn fetch_with(
+    url: &Url,
+    config: &FetchConfig,
+    cache: &Mutex<Cache>,
+    deadline: Instant,
+) -> result::Result<Arc<Vec<u8>>, FetchError> {
+    let networks = config.networks().map_err(FetchError::Blocked)?;
+    //a link that isn't allowed (any more) is refused even if it was downloaded before
+    config.check(url, networks.as_slice())?;
+    let key = url.as_str().to_owned();
+    let cached = cache.lock().unwrap().get(key.as_str());
+    if let Some(ref cached) = cached {
+        if cached.fresh {
+            return Ok(cached.data.clone());
+        }
+    }
+    let ssl = NativeTlsClient::new().map_err(|e| {
+        FetchError::Http(hyper::Error::Ssl(Box::new(e)))
+    })?;
+    let connector = HttpsConnector::with_connector(
+        ssl,
+        TimeoutConnector {
+            timeout: Duration::from_secs(config.connect_timeout),
+            blocked: networks.clone(),
+        },
+    );
+    let mut client = Client::with_connector(connector);
+    //redirects are followed by hand, so they can be counted and checked
+    client.set_redirect_policy(RedirectPolicy::FollowNone);
+    //a single read can't wait past the deadline either
+    let timeout = match remaining(deadline) {
+        Some(left) => left.min(Duration::from_secs(config.read_timeout)),
+        None => return Err(FetchError::TimedOut(config.render_timeout)),
+    };
+    client.set_read_timeout(Some(timeout));
+    client.set_write_timeout(Some(timeout));
+
+    let mut url = url.clone();
+    let mut redirects = 0;
+    loop {
+        if remaining(deadline).is_none() {
+            return Err(FetchError::TimedOut(config.render_timeout));
+        }
+        //ask the server to skip the body if the cached copy is still good
+        let mut request = client.get(url.clone());
+        if let Some(ref cached) = cached {
+            if let Some(ref etag) = cached.validators.etag {
+                request = request.header(IfNoneMatch::Items(vec![etag.clone()]));
+            }
+            if let Some(last_modified) = cached.validators.last_modified {
+                request = request.header(IfModifiedSince(last_modified));
+            }
+        }
+        let mut resp = request.send()?;
+        if resp.status == StatusCode::NotModified {
+            if let Some(ref cached) = cached {
+                cache.lock().unwrap().refresh(
+                    key.as_str(),
+                    max_age(&resp.headers),
+                );
+                return Ok(cached.data.clone());
+            }
+            return Err(FetchError::Status(resp.status));
+        }
+        if resp.status.is_redirection() {
+            let location = match resp.headers.get::<Location>() {
+                Some(location) => location.to_string(),
+                None => return Err(FetchError::Status(resp.status)),
+            };
+            if redirects >= config.max_redirects {
+                return Err(FetchError::TooManyRedirects);
+            }
+            redirects += 1;
+            url = url.join(location.as_str()).map_err(|e| {
+                FetchError::Http(hyper::Error::Uri(e))
+            })?;
+            config.check(&url, networks.as_slice())?;
+            continue;
+        }
+        if !resp.status.is_success() {
+            return Err(FetchError::Status(resp.status));
+        }
+        //don't bother downloading web pages
+        if let Some(&ContentType(Mime(TopLevel::Text, _, _))) = resp.headers.get::<ContentType>() {
+            return Err(FetchError::NotAnImage);
+        }
+        if let Some(&ContentLength(length)) = resp.headers.get::<ContentLength>() {
+            if length > config.max_bytes {
+                return Err(FetchError::TooLarge(config.max_bytes));
+            }
+        }
+        //the length header can lie, so read one byte past the limit to catch oversized bodies. A
+        //chunk at a time, so a slow trickle of data can't keep the download going past the deadline
+        let mut body = Vec::new();
+        {
+            let mut chunk = [0; 8192];
+            let mut limited = resp.by_ref().take(config.max_bytes + 1);
+            loop {
+                match limited.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(read) => body.extend_from_slice(&chunk[..read]),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+                if remaining(deadline).is_none() {
+                    return Err(FetchError::TimedOut(config.render_timeout));
+                }
+            }
+        }
+        if body.len() as u64 > config.max_bytes {
+            return Err(FetchError::TooLarge(config.max_bytes));
+        }
+        //trust the bytes over the headers
+        if image::guess_format(body.as_slice()).is_err() {
+            return Err(FetchError::NotAnImage);
+        }
+        let body = Arc::new(body);
+        if cacheable(&resp.headers) {
+            let validators = Validators {
+                etag: resp.headers.get::<ETag>().map(|x| x.0.clone()),
+                last_modified: resp.headers.get::<LastModified>().map(|x| x.0),
+            };
+            cache.lock().unwrap().insert(
+                key.as_str(),
+                body.clone(),
+                validators,
+                max_age(&resp.headers),
+            );
+        }
+        return Ok(body);
+    }
+}
+
+///How long is left until `deadline`, or None if it has passed.
+fn remaining(deadline: Instant) -> Option<Duration> {
+    let now = Instant::now();
+    if now < deadline {
+        Some(deadline - now)
+    } else {
+        None
+    }
+}
+///Whether the server allows a response to be cached.
+fn cacheable(headers: &Headers) -> bool {
+    match headers.get::<CacheControl>() {
+        Some(&CacheControl(ref directives)) => {
+            !directives.iter().any(|x| *x == CacheDirective::NoStore)
+        }
+        None => true,
+    }
+}
+///How long the server says a response can be reused without checking back, if at all.
+fn max_age(headers: &Headers) -> Option<Duration> {
+    let directives = match headers.get::<CacheControl>() {
+        Some(&CacheControl(ref directives)) => directives,
+        None => return None,
+    };
+    if directives.iter().any(|x| *x == CacheDirective::NoCache) {
+        return None;
+    }
+    directives
+        .iter()
+        .filter_map(|x| match *x {
+            CacheDirective::MaxAge(seconds) => Some(Duration::from_secs(seconds as u64)),
+            _ => None,
+        })
+        .next()
+}
+
+///Opens plain TCP connections, giving up after `timeout` and refusing to connect to blocked
+///networks. HttpsConnector wraps it for TLS.
+struct TimeoutConnector {
+    timeout: Duration,
+    blocked: Vec<Network>,
+}
+impl NetworkConnector for TimeoutConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, _scheme: &str) -> hyper::Result<HttpStream> {
+        let mut last_error = None;
+        for addr in (host, port).to_socket_addrs()? {
+            //the host was checked before the request, but check again in case it resolves
+            //differently this time
+            if is_blocked(addr.ip(), self.blocked.as_slice()) {
+                last_error = Some(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Address is on a blocked network",
+                ));
+                continue;
+            }
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => return Ok(HttpStream(stream)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(
+            last_error
+                .unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "Host has no addresses")
+                })
+                .into(),
+        )
+    }
+}
+
+///A block of IP addresses, like 10.0.0.0/8.
+#[derive(Debug, Clone, Copy)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+impl Network {
+    fn parse(network: &str) -> result::Result<Network, String> {
+        let invalid = || format!("Invalid network {}", network);
+        let mut parts = network.trim().splitn(2, '/');
+        let addr = parts.next().unwrap().parse::<IpAddr>().map_err(
+            |_| invalid(),
+        )?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(Network {
+            addr: addr,
+            prefix: prefix,
+        })
+    }
+    fn contains(&self, ip: IpAddr) -> bool {
+        let prefix = self.prefix as usize;
+        let matches = |a: &[u8], b: &[u8]| {
+            (0..prefix).all(|bit| {
+                let mask = 0x80 >> (bit % 8);
+                a[bit / 8] & mask == b[bit / 8] & mask
+            })
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => matches(&network.octets(), &ip.octets()),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => matches(&network.octets(), &ip.octets()),
+            _ => false,
+        }
+    }
+}
+fn is_blocked(ip: IpAddr, networks: &[Network]) -> bool {
+    //IPv4 addresses tunnelled through IPv6 (::ffff:a.b.c.d) count as the IPv4 address
+    let ip = match ip {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..5].iter().all(|&x| x == 0) && segments[5] == 0xffff {
+                IpAddr::V4(v6.to_ipv4().unwrap())
+            } else {
+                ip
+            }
+        }
+        ip => ip,
+    };
+    networks.iter().any(|network| network.contains(ip))
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    Http(hyper::Error),
+    Io(io::Error),
+    Status(StatusCode),
+    TooManyRedirects,
+    TooLarge(u64),
+    NotAnImage,
+    Blocked(String),
+    TimedOut(u64),
+}
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match *self {
+            FetchError::Http(ref e) => format!("Couldn't download image: {}", e),
+            FetchError::Io(ref e) => format!("Couldn't download image: {}", e),
+            FetchError::Status(ref status) => {
+                format!("Couldn't download image: server said {}", status)
+            }
+            FetchError::TooManyRedirects => "Couldn't download image: too many redirects".to_owned(),
+            FetchError::TooLarge(max_bytes) => {
+                format!("Image is too big, the limit is {} KB", max_bytes / 1024)
+            }
+            FetchError::NotAnImage => "That link isn't an image".to_owned(),
+            FetchError::Blocked(ref reason) => format!("That link isn't allowed: {}", reason),
+            FetchError::TimedOut(seconds) => {
+                format!("Couldn't download every image within {} seconds", seconds)
+            }
+        };
+        write!(f, "{}", message)
+    }
+}
+impl error::Error for FetchError {
+    fn description(&self) -> &str {
+        match *self {
+            FetchError::Http(ref e) => e.description(),
+            FetchError::Io(ref e) => e.description(),
+            FetchError::Status(_) => "The server responded with an error status.",
+            FetchError::TooManyRedirects => "The download was redirected too many times.",
+            FetchError::TooLarge(_) => "The download was larger than the configured limit.",
+            FetchError::NotAnImage => "The download wasn't an image.",
+            FetchError::Blocked(_) => "The link was blocked by the download policy.",
+            FetchError::TimedOut(_) => "The downloads took longer than the render timeout.",
+        }
+    }
+}
+impl FetchError {
+    ///Copies the error, for when one download's result is shared by several features. The
+    ///underlying HTTP and IO errors can't be cloned, so they're copied as IO errors with the same
+    ///message.
+    fn duplicate(&self) -> FetchError {
+        match *self {
+            FetchError::Http(ref e) => {
+                FetchError::Io(io::Error::new(io::ErrorKind::Other, e.to_string()))
+            }
+            FetchError::Io(ref e) => FetchError::Io(io::Error::new(e.kind(), e.to_string())),
+            FetchError::Status(status) => FetchError::Status(status),
+            FetchError::TooManyRedirects => FetchError::TooManyRedirects,
+            FetchError::TooLarge(max_bytes) => FetchError::TooLarge(max_bytes),
+            FetchError::NotAnImage => FetchError::NotAnImage,
+            FetchError::Blocked(ref reason) => FetchError::Blocked(reason.clone()),
+            FetchError::TimedOut(seconds) => FetchError::TimedOut(seconds),
+        }
+    }
+}
+impl From<hyper::Error> for FetchError {
+    fn from(e: hyper::Error) -> FetchError {
+        FetchError::Http(e)
+    }
+}
+impl From<io::Error> for FetchError {
+    fn from(e: io::Error) -> FetchError {
+        FetchError::Io(e)
+    }
+}
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use url::Url;
+
+    use cache::Cache;
+    use fetch::{fetch_all_with, fetch_with, FetchConfig, FetchError};
+
+    const PNG: &[u8] = include_bytes!("../placeholder.png");
+
+    ///Starts a stand-in HTTP server on localhost that answers each connection with the next of
+    ///`responses`. Returns its port, and the requests it receives.
+    fn serve(responses: Vec<String>) -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = channel();
+        thread::spawn(move || for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => request.extend_from_slice(&buf[..read]),
+                }
+            }
+            let _ = sender.send(String::from_utf8_lossy(request.as_slice()).into_owned());
+            let mut response = response.into_bytes();
+            if response.ends_with(b"\r\n\r\n") {
+                response.extend_from_slice(PNG); //headers only, so send the image as the body
+            }
+            let _ = stream.write_all(response.as_slice());
+        });
+        (port, receiver)
+    }
+    fn png_response() -> String {
+        png_response_with("")
+    }
+    ///A PNG response with extra `headers`, each ending in \r\n.
+    fn png_response_with(headers: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+            PNG.len(),
+            headers
+        )
+    }
+    fn redirect_response(location: &str) -> String {
+        format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            location
+        )
+    }
+    ///The default config, minus the localhost block, since that's where the stand-in lives.
+    fn local_config() -> FetchConfig {
+        FetchConfig {
+            blocked_networks: vec!["10.0.0.0/8".to_owned()],
+            ..FetchConfig::default()
+        }
+    }
+    ///A cache of `max_bytes` in an empty directory of its own. A size of 0 never touches the disk.
+    fn temp_cache(name: &str, max_bytes: u64) -> Mutex<Cache> {
+        let dir = env::temp_dir().join(format!("meme_bot_fetch_test_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        Mutex::new(Cache::open(dir, max_bytes))
+    }
+    ///Fetches without a cache.
+    fn fetch_url(url: &str, config: &FetchConfig) -> Result<Vec<u8>, FetchError> {
+        fetch_cached(url, config, &temp_cache("off", 0))
+    }
+    fn fetch_cached(
+        url: &str,
+        config: &FetchConfig,
+        cache: &Mutex<Cache>,
+    ) -> Result<Vec<u8>, FetchError> {
+        let deadline = Instant::now() + Duration::from_secs(config.render_timeout);
+        fetch_with(&Url::parse(url).unwrap(), config, cache, deadline).map(|x| x.as_ref().clone())
+    }
+    fn assert_blocked(result: Result<Vec<u8>, FetchError>) {
+        match result {
+            Err(FetchError::Blocked(_)) => {}
+            other => panic!("expected the fetch to be blocked, got {:?}", other.map(|x| x.len())),
+        }
+    }
+
+    #[test]
+    fn fetches_images() {
+        let (port, _) = serve(vec![png_response()]);
+        let body = fetch_url(&format!("http://127.0.0.1:{}/a.png", port), &local_config()).unwrap();
+        assert_eq!(body.as_slice(), PNG);
+    }
+    #[test]
+    fn blocks_private_networks_and_schemes() {
+        let config = FetchConfig::default();
+        assert_blocked(fetch_url("http://127.0.0.1/a.png", &config));
+        assert_blocked(fetch_url("http://169.254.169.254/latest/meta-data", &config));
+        assert_blocked(fetch_url("http://192.168.1.1/", &config));
+        assert_blocked(fetch_url("http://[::1]/a.png", &config));
+        assert_blocked(fetch_url("http://[::ffff:127.0.0.1]/a.png", &config));
+        //127.0.0.1 through a NAT64 gateway, and through 6to4
+        assert_blocked(fetch_url("http://[64:ff9b::7f00:1]/a.png", &config));
+        assert_blocked(fetch_url("http://[2002:7f00:1::]/a.png", &config));
+        assert_blocked(fetch_url("http://224.0.0.1/", &config));
+        assert_blocked(fetch_url("http://255.255.255.255/", &config));
+        assert_blocked(fetch_url("file:///etc/passwd", &config));
+        assert_blocked(fetch_url("ftp://example.com/a.png", &config));
+    }
+    #[test]
+    fn blocks_redirects_into_private_networks() {
+        let (port, _) = serve(vec![redirect_response("http://10.0.0.1/a.png")]);
+        assert_blocked(fetch_url(
+            &format!("http://127.0.0.1:{}/", port),
+            &local_config(),
+        ));
+    }
+    #[test]
+    fn enforces_domain_allowlist() {
+        let config = FetchConfig {
+            allowed_domains: Some(vec!["discordapp.com".to_owned()]),
+            ..local_config()
+        };
+        let (port, _) = serve(vec![png_response()]);
+        assert_blocked(fetch_url(&format!("http://127.0.0.1:{}/", port), &config));
+        assert_blocked(fetch_url("http://discordapp.com.evil.example/", &config));
+    }
+    #[test]
+    fn limits_redirects_and_size() {
+        let (port, _) = serve(vec![redirect_response("/again"), redirect_response("/again")]);
+        let config = FetchConfig {
+            max_redirects: 1,
+            ..local_config()
+        };
+        match fetch_url(&format!("http://127.0.0.1:{}/", port), &config) {
+            Err(FetchError::TooManyRedirects) => {}
+            other => panic!("expected too many redirects, got {:?}", other.map(|x| x.len())),
+        }
+        let (port, _) = serve(vec![png_response()]);
+        let config = FetchConfig {
+            max_bytes: 16,
+            ..local_config()
+        };
+        match fetch_url(&format!("http://127.0.0.1:{}/", port), &config) {
+            Err(FetchError::TooLarge(16)) => {}
+            other => panic!("expected a size error, got {:?}", other.map(|x| x.len())),
+        }
+        //so is a body without a Content-Length
+        let (port, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nConnection: close\r\n\r\n"
+                .to_owned(),
+        ]);
+        match fetch_url(&format!("http://127.0.0.1:{}/", port), &config) {
+            Err(FetchError::TooLarge(16)) => {}
+            other => panic!("expected a size error, got {:?}", other.map(|x| x.len())),
+        }
+    }
+    #[test]
+    fn rejects_non_images() {
+        let (port, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 4\r\n\r\n<p/>"
+                .to_owned(),
+        ]);
+        match fetch_url(&format!("http://127.0.0.1:{}/", port), &local_config()) {
+            Err(FetchError::NotAnImage) => {}
+            other => panic!("expected a type error, got {:?}", other.map(|x| x.len())),
+        }
+        //a mislabeled body is sniffed, whatever the header says
+        let (port, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 4\r\n\r\nnope"
+                .to_owned(),
+        ]);
+        match fetch_url(&format!("http://127.0.0.1:{}/", port), &local_config()) {
+            Err(FetchError::NotAnImage) => {}
+            other => panic!("expected a type error, got {:?}", other.map(|x| x.len())),
+        }
+    }
+    #[test]
+    fn revalidates_cached_images() {
+        let (port, requests) = serve(vec![
+            png_response_with("ETag: \"avatar\"\r\n"),
+            "HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_owned(),
+        ]);
+        let url = format!("http://127.0.0.1:{}/avatar.png", port);
+        let cache = temp_cache("revalidates", 1024 * 1024);
+        let first = fetch_cached(url.as_str(), &local_config(), &cache).unwrap();
+        let second = fetch_cached(url.as_str(), &local_config(), &cache).unwrap();
+        assert_eq!(first.as_slice(), PNG);
+        assert_eq!(second.as_slice(), PNG);
+        let requests = requests.iter().take(2).collect::<Vec<String>>();
+        assert!(!requests[0].contains("If-None-Match"));
+        assert!(requests[1].contains("If-None-Match: \"avatar\""));
+    }
+    #[test]
+    fn reuses_fresh_images() {
+        //only one response, so a second request would fail
+        let (port, _) = serve(vec![png_response_with("Cache-Control: max-age=60\r\n")]);
+        let url = format!("http://127.0.0.1:{}/avatar.png", port);
+        let cache = temp_cache("fresh", 1024 * 1024);
+        fetch_cached(url.as_str(), &local_config(), &cache).unwrap();
+        let second = fetch_cached(url.as_str(), &local_config(), &cache).unwrap();
+        assert_eq!(second.as_slice(), PNG);
+        //the cached copy doesn't get around the rules once they change
+        let config = FetchConfig {
+            allowed_domains: Some(vec!["example.com".to_owned()]),
+            ..local_config()
+        };
+        assert_blocked(fetch_cached(url.as_str(), &config, &cache));
+        assert_blocked(fetch_cached(url.as_str(), &FetchConfig::default(), &cache));
+    }
+    #[test]
+    fn stops_slow_downloads_at_the_deadline() {
+        //sends a byte at a time, each well within the read timeout
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 100\r\n\r\n",
+            );
+            for byte in PNG.iter().take(100) {
+                thread::sleep(Duration::from_millis(100));
+                if stream.write_all(&[*byte]).is_err() {
+                    break;
+                }
+            }
+        });
+        let config = FetchConfig {
+            render_timeout: 1,
+            ..local_config()
+        };
+        let start = Instant::now();
+        match fetch_url(&format!("http://127.0.0.1:{}/", port), &config) {
+            Err(FetchError::TimedOut(1)) => {}
+            other => panic!("expected a timeout, got {:?}", other.map(|x| x.len())),
+        }
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+    #[test]
+    fn fetches_concurrently_within_deadline() {
+        let (port, _) = serve(vec![png_response()]);
+        //accepts connections, but never answers
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_port = silent.local_addr().unwrap().port();
+        let config = FetchConfig {
+            render_timeout: 1,
+            ..local_config()
+        };
+        let fast = Url::parse(format!("http://127.0.0.1:{}/", port).as_str()).unwrap();
+        let slow = Url::parse(format!("http://127.0.0.1:{}/", silent_port).as_str()).unwrap();
+        let start = Instant::now();
+        let results = fetch_all_with(
+            &[fast.clone(), slow, fast],
+            &config,
+            &Arc::new(temp_cache("concurrent", 0)),
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().as_slice(), PNG);
+        assert_eq!(results[2].as_ref().unwrap().as_slice(), PNG);
+        match results[1] {
+            Err(FetchError::TimedOut(1)) => {}
+            ref other => panic!("expected a timeout, got {:?}", other.as_ref().map(|x| x.len())),
+        }
+    }
+    #[test]
+    fn parses_networks() {
+        let config = FetchConfig {
+            blocked_networks: vec!["10.0.0.0/33".to_owned()],
+            ..FetchConfig::default()
+        };
+        assert!(config.networks().is_err());
+        assert!(FetchConfig::default().networks().is_ok());
+    }
+}
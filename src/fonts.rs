@@ -0,0 +1,173 @@
+///Fonts module: a registry that loads each font once and shares it between templates and renders.
+///A font can come with bold and italic variants, found next to it as `<name>-bold.ttf`,
+///`<name>-italic.ttf` and `<name>-bolditalic.ttf`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rusttype::{Font, FontCollection};
+
+use template::{Error, Result};
+
+const DEFAULT_FONT: &[u8] = include_bytes!("Roboto.ttf");
+///Where fonts that can be picked by name live, as `<name>.ttf`.
+pub const FONTS_DIR: &str = "./fonts";
+
+lazy_static! {
+    static ref DEFAULT: Arc<Font<'static>> =
+        Arc::new(parse(Vec::from(DEFAULT_FONT)).expect("Bundled font is invalid"));
+    static ref FONTS: RwLock<HashMap<PathBuf, Arc<Font<'static>>>> = RwLock::new(HashMap::new());
+}
+
+///A font along with whichever of its bold and italic variants it has.
+#[derive(Clone)]
+pub struct Family {
+    pub regular: Arc<Font<'static>>,
+    pub bold: Option<Arc<Font<'static>>>,
+    pub italic: Option<Arc<Font<'static>>>,
+    pub bold_italic: Option<Arc<Font<'static>>>,
+}
+///The font to draw a style with, and what it's missing and has to be faked.
+pub struct Face<'a> {
+    pub font: &'a Arc<Font<'static>>,
+    pub fake_bold: bool,
+    pub fake_italic: bool,
+}
+impl<'a> Face<'a> {
+    fn new(font: &'a Arc<Font<'static>>, fake_bold: bool, fake_italic: bool) -> Face<'a> {
+        Face {
+            font: font,
+            fake_bold: fake_bold,
+            fake_italic: fake_italic,
+        }
+    }
+}
+impl Family {
+    ///A family with just the one font.
+    pub fn single(font: Arc<Font<'static>>) -> Family {
+        Family {
+            regular: font,
+            bold: None,
+            italic: None,
+            bold_italic: None,
+        }
+    }
+    ///The closest variant for text that's `bold` and/or `italic`.
+    pub fn select(&self, bold: bool, italic: bool) -> Face {
+        let face = Face::new;
+        match (bold, italic) {
+            (false, false) => face(&self.regular, false, false),
+            (true, false) => match self.bold {
+                Some(ref font) => face(font, false, false),
+                None => face(&self.regular, true, false),
+            },
+            (false, true) => match self.italic {
+                Some(ref font) => face(font, false, false),
+                None => face(&self.regular, false, true),
+            },
+            (true, true) => match (&self.bold_italic, &self.bold, &self.italic) {
+                (&Some(ref font), _, _) => face(font, false, false),
+                (_, &Some(ref font), _) => face(font, false, true),
+                (_, _, &Some(ref font)) => face(font, true, false),
+                _ => face(&self.regular, true, true),
+            },
+        }
+    }
+}
+
+///The bundled font (Roboto), used when neither the template nor the feature names one.
+pub fn default_font() -> Arc<Font<'static>> {
+    DEFAULT.clone()
+}
+///The bundled font, with any variants of it in FONTS_DIR (like `roboto-bold.ttf`). Only the
+///regular style is bundled.
+pub fn default_family() -> Result<Family> {
+    let path = Path::new(FONTS_DIR).join("roboto.ttf");
+    Ok(Family {
+        regular: default_font(),
+        bold: default_variant(&path, "bold", "Roboto-Bold.ttf")?,
+        italic: default_variant(&path, "italic", "Roboto-Italic.ttf")?,
+        bold_italic: default_variant(&path, "bolditalic", "Roboto-BoldItalic.ttf")?,
+    })
+}
+///Loads the font at `path`, or returns the already-loaded copy if it's been loaded before.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Arc<Font<'static>>> {
+    let path = path.as_ref();
+    if let Some(font) = FONTS.read().unwrap().get(path) {
+        return Ok(font.clone());
+    }
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let font = Arc::new(parse(data).ok_or_else(|| {
+        Error::Invalid(format!(
+            "{} is not a valid font",
+            path.to_string_lossy()
+        ))
+    })?);
+    FONTS.write().unwrap().insert(path.to_owned(), font.clone());
+    Ok(font)
+}
+///Loads the font at `path` along with its variants.
+pub fn family<P: AsRef<Path>>(path: P) -> Result<Family> {
+    let path = path.as_ref();
+    Ok(Family {
+        regular: load(path)?,
+        bold: variant(path, "bold")?,
+        italic: variant(path, "italic")?,
+        bold_italic: variant(path, "bolditalic")?,
+    })
+}
+///Loads the font called `name` and its variants from FONTS_DIR. `roboto` is always available,
+///since it's bundled.
+pub fn named(name: &str) -> Result<Family> {
+    let name = name.to_lowercase();
+    if name == "roboto" {
+        return default_family();
+    }
+    //names end up in a path, so keep them to plain file names
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+        return Err(Error::Invalid(format!("{} isn't a valid font name", name)));
+    }
+    let path = Path::new(FONTS_DIR).join(format!("{}.ttf", name));
+    if !path.is_file() {
+        return Err(Error::Invalid(format!("There's no font called {}", name)));
+    }
+    family(path)
+}
+///Loads the `variant` of the font at `path`, if there's a file for it.
+fn variant(path: &Path, variant: &str) -> Result<Option<Arc<Font<'static>>>> {
+    let stem = match path.file_stem().and_then(|x| x.to_str()) {
+        Some(stem) => stem,
+        None => return Ok(None),
+    };
+    let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("ttf");
+    let path = path.with_file_name(format!("{}-{}.{}", stem, variant, extension));
+    if path.is_file() {
+        load(path).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+///Loads a variant of the bundled Roboto from the fonts folder, named either like any other
+///variant (`roboto-bold.ttf`) or the way Google ships it (`Roboto-Bold.ttf`).
+fn default_variant(
+    path: &Path,
+    name: &str,
+    shipped_name: &str,
+) -> Result<Option<Arc<Font<'static>>>> {
+    if let Some(font) = variant(path, name)? {
+        return Ok(Some(font));
+    }
+    let shipped = path.with_file_name(shipped_name);
+    if shipped.is_file() {
+        load(shipped).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+fn parse(data: Vec<u8>) -> Option<Font<'static>> {
+    FontCollection::from_bytes(data).into_font()
+}
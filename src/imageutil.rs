@@ -5,7 +5,17 @@ use image::Pixel;
 use image::GrayImage;
 use image::RgbaImage;
 use image::Rgba;
+use image::ColorType;
+use image::jpeg::JPEGEncoder;
 use std::cmp::min;
+use std::f32;
+
+///How much deep_fry multiplies saturation by.
+const FRY_SATURATION: f32 = 2.0;
+///How much deep_fry multiplies contrast by.
+const FRY_CONTRAST: f32 = 1.6;
+///The JPEG quality deep_fry compresses with, out of 100.
+const FRY_QUALITY: u8 = 8;
 
 pub fn paste_image<D: GenericImage + 'static, S: GenericImage<Pixel = D::Pixel> + 'static>(
     source: &S,
@@ -48,6 +58,60 @@ pub fn paste_image_resized<
         }
     }
 }
+///Resizes `source` to `w` by `h` and pastes it with its top-left corner at (`x`, `y`), which may
+///be off the edge. Only the part landing inside `clip` (x, y, width, height) is drawn.
+pub fn paste_image_resized_clipped(
+    source: &RgbaImage,
+    destination: &mut RgbaImage,
+    x: i32,
+    y: i32,
+    w: u32,
+    h: u32,
+    clip: (u32, u32, u32, u32),
+) {
+    let resized = resize(source, w, h, image::FilterType::Nearest); //resize image
+    let right = min(clip.0 + clip.2, destination.width()) as i32;
+    let bottom = min(clip.1 + clip.3, destination.height()) as i32;
+
+    for i in 0..resized.width() {
+        for k in 0..resized.height() {
+            let (dx, dy) = (i as i32 + x, k as i32 + y);
+            if dx >= clip.0 as i32 && dy >= clip.1 as i32 && dx < right && dy < bottom {
+                let pixel = resized.get_pixel(i, k);
+                let mut other_pixel = *destination.get_pixel(dx as u32, dy as u32);
+                other_pixel.blend(pixel);
+                destination.put_pixel(dx as u32, dy as u32, other_pixel);
+            }
+        }
+    }
+}
+///"Deep fries" an image: cranks up the saturation and contrast, then runs it through a very
+///low-quality JPEG for the compression artifacts. Transparency is kept as it was.
+pub fn deep_fry(source: &RgbaImage) -> image::ImageResult<RgbaImage> {
+    let mut fried = source.clone();
+    for pixel in fried.pixels_mut() {
+        let p = &mut pixel.data;
+        let gray = 0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32;
+        for channel in p.iter_mut().take(3) {
+            let saturated = gray + (*channel as f32 - gray) * FRY_SATURATION;
+            let contrasted = (saturated - 128.0) * FRY_CONTRAST + 128.0;
+            *channel = contrasted.max(0.0).min(255.0) as u8;
+        }
+    }
+    let (width, height) = fried.dimensions();
+    let mut jpeg = Vec::new();
+    JPEGEncoder::new_with_quality(&mut jpeg, FRY_QUALITY).encode(
+        &fried,
+        width,
+        height,
+        ColorType::RGBA(8),
+    )?;
+    let mut fried = image::load_from_memory(jpeg.as_slice())?.to_rgba();
+    for (pixel, original) in fried.pixels_mut().zip(source.pixels()) {
+        pixel.data[3] = original.data[3];
+    }
+    Ok(fried)
+}
 pub fn mask_image(input_image: RgbaImage, mask_image: &GrayImage) -> RgbaImage {
     assert_eq!(input_image.width(), mask_image.width());
     assert_eq!(input_image.height(), mask_image.height());
@@ -69,3 +133,177 @@ pub fn mask_image(input_image: RgbaImage, mask_image: &GrayImage) -> RgbaImage {
     }
     output_image
 }
+///Computes the projective transform (homography) mapping each of the four `from` points onto the
+///matching `to` point. The result is a row-major 3x3 matrix, or None if the points are degenerate
+///(e.g. three of them lie on a line).
+pub fn homography(from: &[(f32, f32); 4], to: &[(f32, f32); 4]) -> Option<[f64; 9]> {
+    //two equations per point pair, solved for the 8 unknowns (the 9th entry is fixed at 1)
+    let mut system = [[0f64; 9]; 8];
+    for i in 0..4 {
+        let (x, y) = (from[i].0 as f64, from[i].1 as f64);
+        let (u, v) = (to[i].0 as f64, to[i].1 as f64);
+        system[i * 2] = [x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u, u];
+        system[i * 2 + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v, v];
+    }
+    //gaussian elimination with partial pivoting
+    for column in 0..8 {
+        let pivot = (column..8)
+            .max_by(|&a, &b| {
+                system[a][column]
+                    .abs()
+                    .partial_cmp(&system[b][column].abs())
+                    .unwrap()
+            })
+            .unwrap();
+        if system[pivot][column].abs() < 1e-9 {
+            return None;
+        }
+        system.swap(column, pivot);
+        for row in 0..8 {
+            if row != column {
+                let factor = system[row][column] / system[column][column];
+                for k in column..9 {
+                    system[row][k] -= factor * system[column][k];
+                }
+            }
+        }
+    }
+    let mut matrix = [1f64; 9];
+    for i in 0..8 {
+        matrix[i] = system[i][8] / system[i][i];
+    }
+    //elimination can still succeed when the result squashes everything onto a line, so check the
+    //determinant too, relative to the size of the rows so it doesn't depend on scale
+    let det = matrix[0] * (matrix[4] * matrix[8] - matrix[5] * matrix[7]) -
+        matrix[1] * (matrix[3] * matrix[8] - matrix[5] * matrix[6]) +
+        matrix[2] * (matrix[3] * matrix[7] - matrix[4] * matrix[6]);
+    let size = (0..3)
+        .map(|row| {
+            matrix[row * 3..row * 3 + 3]
+                .iter()
+                .map(|x| x * x)
+                .sum::<f64>()
+                .sqrt()
+        })
+        .product::<f64>();
+    if size == 0.0 || (det / size).abs() < 1e-9 {
+        return None;
+    }
+    Some(matrix)
+}
+///Whether `quad` is convex and its edges don't cross: every turn from one edge to the next goes
+///the same way.
+pub fn is_convex(quad: &[(f32, f32); 4]) -> bool {
+    let mut sign = 0.0;
+    for i in 0..4 {
+        let (a, b, c) = (quad[i], quad[(i + 1) % 4], quad[(i + 2) % 4]);
+        let cross = (b.0 - a.0) * (c.1 - b.1) - (b.1 - a.1) * (c.0 - b.0);
+        if cross.abs() < 1e-6 || cross * sign < 0.0 {
+            return false;
+        }
+        sign = cross;
+    }
+    true
+}
+///Warps the `w` by `h` area of `source` at (`x`, `y`) onto the quadrilateral `corners` (top-left,
+///top-right, bottom-right, bottom-left). The result is the same size as `source`, transparent
+///outside of the quadrilateral.
+pub fn warp_perspective(
+    source: &RgbaImage,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    corners: &[(f32, f32); 4],
+) -> Option<RgbaImage> {
+    let (x, y, w, h) = (x as f32, y as f32, w as f32, h as f32);
+    let rect = [(x, y), (x + w, y), (x + w, y + h), (x, y + h)];
+    //map backwards from every destination pixel to where it comes from in the source
+    let matrix = homography(corners, &rect)?;
+    let mut output = RgbaImage::new(source.width(), source.height());
+    let min_x = corners.iter().fold(f32::MAX, |m, c| m.min(c.0)).max(0.0) as u32;
+    let min_y = corners.iter().fold(f32::MAX, |m, c| m.min(c.1)).max(0.0) as u32;
+    let max_x = (corners.iter().fold(0f32, |m, c| m.max(c.0)).ceil() as u32).min(output.width());
+    let max_y = (corners.iter().fold(0f32, |m, c| m.max(c.1)).ceil() as u32).min(output.height());
+    for i in min_x..max_x {
+        for k in min_y..max_y {
+            let (px, py) = (i as f64 + 0.5, k as f64 + 0.5);
+            let z = matrix[6] * px + matrix[7] * py + matrix[8];
+            let u = (matrix[0] * px + matrix[1] * py + matrix[2]) / z;
+            let v = (matrix[3] * px + matrix[4] * py + matrix[5]) / z;
+            if u < x as f64 || v < y as f64 || u >= (x + w) as f64 || v >= (y + h) as f64 {
+                continue; //outside the quadrilateral
+            }
+            output.put_pixel(i, k, sample_bilinear(source, u as f32 - 0.5, v as f32 - 0.5));
+        }
+    }
+    Some(output)
+}
+///Samples `image` at a fractional position, blending the four nearest pixels. Pixels past the
+///edges count as transparent.
+fn sample_bilinear(image: &RgbaImage, x: f32, y: f32) -> Rgba<u8> {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let mut channels = [0f32; 4];
+    for &(dx, dy, weight) in &[
+        (0, 0, (1.0 - fx) * (1.0 - fy)),
+        (1, 0, fx * (1.0 - fy)),
+        (0, 1, (1.0 - fx) * fy),
+        (1, 1, fx * fy),
+    ]
+    {
+        let (px, py) = (x0 as i64 + dx, y0 as i64 + dy);
+        if px < 0 || py < 0 || px >= image.width() as i64 || py >= image.height() as i64 {
+            continue;
+        }
+        let pixel = image.get_pixel(px as u32, py as u32);
+        //weight colour by alpha so transparent neighbours don't darken the edges
+        let alpha = pixel.data[3] as f32 * weight;
+        for c in 0..3 {
+            channels[c] += pixel.data[c] as f32 * alpha;
+        }
+        channels[3] += alpha;
+    }
+    if channels[3] <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    Rgba(
+        [
+            (channels[0] / channels[3]).round().min(255.0) as u8,
+            (channels[1] / channels[3]).round().min(255.0) as u8,
+            (channels[2] / channels[3]).round().min(255.0) as u8,
+            channels[3].round().min(255.0) as u8,
+        ],
+    )
+}
+#[cfg(test)]
+mod test {
+    use imageutil::{homography, is_convex};
+
+    #[test]
+    fn homography_maps_corners() {
+        let from = [(0.0, 0.0), (100.0, 0.0), (100.0, 50.0), (0.0, 50.0)];
+        let to = [(10.0, 20.0), (120.0, 5.0), (110.0, 90.0), (0.0, 70.0)];
+        let m = homography(&from, &to).unwrap();
+        for i in 0..4 {
+            let (x, y) = (from[i].0 as f64, from[i].1 as f64);
+            let z = m[6] * x + m[7] * y + m[8];
+            assert!(((m[0] * x + m[1] * y + m[2]) / z - to[i].0 as f64).abs() < 1e-3);
+            assert!(((m[3] * x + m[4] * y + m[5]) / z - to[i].1 as f64).abs() < 1e-3);
+        }
+        //three points on a line can't make a quadrilateral
+        let line = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (0.0, 5.0)];
+        assert!(homography(&from, &line).is_none());
+    }
+    #[test]
+    fn convex_quads() {
+        assert!(is_convex(&[(0.0, 0.0), (100.0, 0.0), (100.0, 50.0), (0.0, 50.0)]));
+        //either winding is fine
+        assert!(is_convex(&[(0.0, 50.0), (100.0, 50.0), (100.0, 0.0), (0.0, 0.0)]));
+        assert!(is_convex(&[(10.0, 20.0), (120.0, 5.0), (110.0, 90.0), (0.0, 70.0)]));
+        //crossed edges (a bowtie), a dent, and three points on a line
+        assert!(!is_convex(&[(0.0, 0.0), (100.0, 50.0), (100.0, 0.0), (0.0, 50.0)]));
+        assert!(!is_convex(&[(0.0, 0.0), (100.0, 0.0), (20.0, 20.0), (0.0, 100.0)]));
+        assert!(!is_convex(&[(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (0.0, 5.0)]));
+    }
+}
@@ -0,0 +1,331 @@
+///Layout module: measures text with the font's real glyph metrics, so it can be wrapped and
+///aligned by pixel instead of by character count.
+
+use rusttype::{Codepoint, Font, Scale};
+
+use emoji;
+use emoji::Piece;
+use fonts::Family;
+use markup;
+use markup::{Run, Style};
+use template::{Alignment, VerticalAlignment};
+
+///The width of `text` in pixels when drawn with `font` at `scale`, kerning included. Emoji from the
+///emoji set count as squares, see `emoji_size`.
+pub fn text_width(font: &Font, scale: Scale, text: &str) -> f32 {
+    emoji::split(text)
+        .into_iter()
+        .map(|piece| match piece {
+            Piece::Text(text) => glyphs_width(font, scale, text),
+            Piece::Emoji(_) => emoji_size(scale),
+        })
+        .sum()
+}
+///How wide and tall an emoji is drawn inline with text at `scale`: as tall as the font size.
+pub fn emoji_size(scale: Scale) -> f32 {
+    scale.y
+}
+fn glyphs_width(font: &Font, scale: Scale, text: &str) -> f32 {
+    let mut width = 0.0;
+    let mut last = None;
+    for c in text.chars() {
+        if let Some(glyph) = font.glyph(Codepoint(c as u32)) {
+            let glyph = glyph.scaled(scale);
+            if let Some(last) = last {
+                width += font.pair_kerning(scale, last, glyph.id());
+            }
+            width += glyph.h_metrics().advance_width;
+            last = Some(glyph.id());
+        }
+    }
+    width
+}
+///The width of a line of styled runs drawn with `fonts` at `scale`. Styles the family has no font
+///for count as wide as they're drawn when faked (see `bold_offset`).
+pub fn runs_width(fonts: &Family, scale: Scale, runs: &[Run]) -> f32 {
+    runs.iter()
+        .map(|run| {
+            let face = fonts.select(run.style.bold, run.style.italic);
+            let extra = if face.fake_bold {
+                bold_offset(scale) as f32
+            } else {
+                0.0
+            };
+            text_width(face.font, scale, run.text.as_str()) + extra
+        })
+        .sum()
+}
+///How many pixels bold text is smeared to the right when faking it with a regular font.
+pub fn bold_offset(scale: Scale) -> u32 {
+    (scale.y / 20.0).round().max(1.0) as u32
+}
+///Wraps `text` into lines no wider than `max_width` pixels. Line breaks in `text` always start a
+///new line (blank lines included); the text between them is wrapped on its own.
+pub fn wrap(font: &Font, scale: Scale, text: &str, max_width: f32) -> Vec<String> {
+    wrap_runs(&[Run::plain(text)], max_width, |line| plain_width(font, scale, line))
+        .iter()
+        .map(|line| markup::plain_text(line))
+        .collect()
+}
+///Wraps styled `runs` like `wrap`, measuring each line with `measure`. Words are kept whole where
+///possible; a word too wide for a line of its own is broken wherever it overflows.
+pub fn wrap_runs<M: Fn(&[Run]) -> f32>(runs: &[Run], max_width: f32, measure: M) -> Vec<Vec<Run>> {
+    let chars = runs.iter()
+        .flat_map(|run| run.text.chars().map(move |c| (c, run.style)))
+        .filter(|&(c, _)| c != '\r')
+        .collect::<Vec<(char, Style)>>();
+    let mut paragraphs = chars.split(|&(c, _)| c == '\n').collect::<Vec<_>>();
+    //like str::lines, a line break at the very end doesn't start another line
+    if chars.last().map_or(true, |&(c, _)| c == '\n') {
+        paragraphs.pop();
+    }
+    let mut lines = Vec::new();
+    for paragraph in paragraphs {
+        wrap_paragraph(paragraph, max_width, &measure, &mut lines);
+    }
+    lines
+}
+fn wrap_paragraph<M: Fn(&[Run]) -> f32>(
+    paragraph: &[(char, Style)],
+    max_width: f32,
+    measure: &M,
+    lines: &mut Vec<Vec<Run>>,
+) {
+    //each word, along with the style of the space before it
+    let mut words = Vec::new();
+    let mut space = Style::default();
+    let mut word = Vec::new();
+    for &(c, style) in paragraph {
+        if c.is_whitespace() {
+            if !word.is_empty() {
+                words.push((space, word));
+                word = Vec::new();
+            }
+            space = style;
+        } else {
+            word.push((c, style));
+        }
+    }
+    if !word.is_empty() {
+        words.push((space, word));
+    }
+    let first_line = lines.len();
+    let mut line: Vec<(char, Style)> = Vec::new();
+    for (space, word) in words {
+        let mut candidate = line.clone();
+        if !candidate.is_empty() {
+            candidate.push((' ', space));
+        }
+        candidate.extend(word.iter().cloned());
+        if measure(&to_runs(&candidate)) <= max_width {
+            line = candidate;
+            continue;
+        }
+        //the word doesn't fit on this line, so it starts the next one
+        if !line.is_empty() {
+            lines.push(to_runs(&line));
+            line = Vec::new();
+        }
+        for &c in &word {
+            line.push(c);
+            if line.len() > 1 && measure(&to_runs(&line)) > max_width {
+                line.pop();
+                lines.push(to_runs(&line));
+                line = vec![c];
+            }
+        }
+    }
+    //a paragraph with nothing in it is still a (blank) line
+    if !line.is_empty() || lines.len() == first_line {
+        lines.push(to_runs(&line));
+    }
+}
+///Groups styled characters back into runs.
+fn to_runs(chars: &[(char, Style)]) -> Vec<Run> {
+    let mut runs: Vec<Run> = Vec::new();
+    for &(c, style) in chars {
+        if let Some(run) = runs.last_mut() {
+            if run.style == style {
+                run.text.push(c);
+                continue;
+            }
+        }
+        runs.push(Run {
+            text: c.to_string(),
+            style: style,
+        });
+    }
+    runs
+}
+fn plain_width(font: &Font, scale: Scale, runs: &[Run]) -> f32 {
+    runs.iter().map(|run| text_width(font, scale, run.text.as_str())).sum()
+}
+///Finds the biggest whole font size between `min_size` and `max_size` at which `text` wraps into
+///a `max_width` by `max_height` box. Returns the size along with the wrapped lines, or None if the
+///text doesn't fit even at `min_size`.
+pub fn fit(
+    font: &Font,
+    text: &str,
+    max_width: f32,
+    max_height: f32,
+    min_size: f32,
+    max_size: f32,
+) -> Option<(f32, Vec<String>)> {
+    fit_runs(
+        &[Run::plain(text)],
+        max_width,
+        max_height,
+        min_size,
+        max_size,
+        |scale, line| plain_width(font, scale, line),
+    ).map(|(size, lines)| {
+        (size, lines.iter().map(|line| markup::plain_text(line)).collect())
+    })
+}
+///Like `fit`, but for styled `runs`, measuring lines at a given scale with `measure`.
+pub fn fit_runs<M: Fn(Scale, &[Run]) -> f32>(
+    runs: &[Run],
+    max_width: f32,
+    max_height: f32,
+    min_size: f32,
+    max_size: f32,
+    measure: M,
+) -> Option<(f32, Vec<Vec<Run>>)> {
+    let try_size = |size: f32| {
+        let scale = Scale { x: size, y: size };
+        let lines = wrap_runs(runs, max_width, |line| measure(scale, line));
+        if lines.len() as f32 * size <= max_height &&
+            lines.iter().all(|line| measure(scale, line) <= max_width)
+        {
+            Some(lines)
+        } else {
+            None
+        }
+    };
+    if let Some(lines) = try_size(max_size) {
+        return Some((max_size, lines));
+    }
+    //binary search over the whole sizes below max_size
+    let mut best = None;
+    let mut low = min_size.ceil() as u32;
+    let mut high = max_size.ceil() as u32 - 1;
+    while low <= high {
+        let mid = (low + high) / 2;
+        match try_size(mid as f32) {
+            Some(lines) => {
+                best = Some((mid as f32, lines));
+                low = mid + 1;
+            }
+            None => {
+                if mid == 0 {
+                    break;
+                }
+                high = mid - 1;
+            }
+        }
+    }
+    best.or_else(|| try_size(min_size).map(|lines| (min_size, lines)))
+}
+///How far a line `line_width` pixels wide is shifted right to align it within `max_width` pixels.
+pub fn align_offset(line_width: f32, max_width: f32, alignment: Alignment) -> f32 {
+    let space = (max_width - line_width).max(0.0);
+    match alignment {
+        Alignment::Left => 0.0,
+        Alignment::Center => (space / 2.0).floor(),
+        Alignment::Right => space.floor(),
+    }
+}
+///How far a block of lines `block_height` pixels tall is shifted down to align it within
+///`max_height` pixels.
+pub fn vertical_offset(block_height: f32, max_height: f32, alignment: VerticalAlignment) -> f32 {
+    let space = (max_height - block_height).max(0.0);
+    match alignment {
+        VerticalAlignment::Top => 0.0,
+        VerticalAlignment::Middle => (space / 2.0).floor(),
+        VerticalAlignment::Bottom => space.floor(),
+    }
+}
+#[cfg(test)]
+mod test {
+    use rusttype::Scale;
+
+    use fonts::{default_font, Family};
+    use layout::{align_offset, bold_offset, fit, fit_runs, runs_width, text_width, wrap};
+    use markup;
+    use template::Alignment;
+
+    #[test]
+    fn wrapped_lines_fit() {
+        let font = default_font();
+        let scale = Scale { x: 30.0, y: 30.0 };
+        let lines = wrap(
+            &font,
+            scale,
+            "you can't rewrite everything in rust, supercalifragilisticexpialidocious",
+            200.0,
+        );
+        assert!(lines.len() > 1);
+        for line in &lines {
+            assert!(text_width(&font, scale, line.as_str()) <= 200.0);
+        }
+    }
+    #[test]
+    fn hard_breaks() {
+        let font = default_font();
+        let scale = Scale { x: 30.0, y: 30.0 };
+        assert_eq!(
+            wrap(&font, scale, "top\n\nbottom text", 1000.0),
+            vec!["top".to_owned(), String::new(), "bottom text".to_owned()]
+        );
+        //each paragraph still wraps on its own
+        let lines = wrap(&font, scale, "a\nthe quick brown fox jumps", 150.0);
+        assert_eq!(lines[0], "a");
+        assert!(lines.len() > 2);
+    }
+    #[test]
+    fn fit_picks_biggest_size() {
+        let font = default_font();
+        //short text fits at the maximum size
+        let (size, lines) = fit(&font, "hi", 400.0, 400.0, 8.0, 40.0).unwrap();
+        assert_eq!(size, 40.0);
+        assert_eq!(lines, vec!["hi".to_owned()]);
+        //longer text has to shrink, but stays within bounds
+        let text = "the quick brown fox jumps over the lazy dog again and again";
+        let (size, lines) = fit(&font, text, 200.0, 60.0, 8.0, 40.0).unwrap();
+        assert!(size < 40.0 && size >= 8.0);
+        assert!(lines.len() as f32 * size <= 60.0);
+        //and too much text doesn't fit at all
+        assert!(fit(&font, text, 20.0, 10.0, 8.0, 40.0).is_none());
+    }
+    #[test]
+    fn styled_runs_wrap() {
+        let fonts = Family::single(default_font());
+        let scale = Scale { x: 30.0, y: 30.0 };
+        let runs = markup::parse("plain **bold words** ~~struck~~");
+        //without a bold font, bold text is smeared wider
+        let bold = markup::parse("**bold words**");
+        assert_eq!(
+            runs_width(&fonts, scale, &bold),
+            text_width(&default_font(), scale, "bold words") + bold_offset(scale) as f32
+        );
+        let (_, lines) = fit_runs(&runs, 150.0, 400.0, 30.0, 30.0, |scale, line| {
+            runs_width(&fonts, scale, line)
+        }).unwrap();
+        assert!(lines.len() > 1);
+        //the markers are gone, and the styles stay with their words
+        let words = lines.iter().flat_map(|line| line.iter()).collect::<Vec<_>>();
+        assert!(words.iter().any(|run| run.style.bold && run.text.contains("bold")));
+        assert!(words.iter().all(|run| !run.text.contains('*') && !run.text.contains('~')));
+        assert_eq!(
+            lines.iter().map(|line| markup::plain_text(line)).collect::<Vec<_>>().join(" "),
+            "plain bold words struck"
+        );
+    }
+    #[test]
+    fn alignment_offsets() {
+        assert_eq!(align_offset(50.0, 200.0, Alignment::Left), 0.0);
+        assert_eq!(align_offset(50.0, 200.0, Alignment::Center), 75.0);
+        assert_eq!(align_offset(50.0, 200.0, Alignment::Right), 150.0);
+        assert_eq!(align_offset(250.0, 200.0, Alignment::Center), 0.0);
+    }
+}
@@ -0,0 +1,42 @@
+//!Rendering side of MemeBot: loads templates and renders memes from them, independent of Discord.
+//!
+//!```no_run
+//!extern crate meme_bot;
+//!
+//!let templates = meme_bot::template::load_templates("./templates").unwrap();
+//!let template = meme_bot::template::get_template(&templates, "rare").unwrap();
+//!let texts = meme_bot::parse_text(&["\"a farmed jungle legion\""]).unwrap();
+//!let texts = texts.iter().map(|x| x.as_str()).collect::<Vec<&str>>();
+//!let options = meme_bot::RenderOptions::default();
+//!let (bytes, extension) = template.render(texts.as_slice(), &options).unwrap().encode().unwrap();
+//!```
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate image;
+extern crate imageproc;
+extern crate rusttype;
+extern crate hyper;
+extern crate url;
+extern crate hyper_native_tls;
+extern crate gif;
+#[macro_use]
+extern crate lazy_static;
+
+pub mod template;
+pub mod parse;
+pub mod imageutil;
+pub mod animation;
+pub mod fonts;
+pub mod layout;
+pub mod crop;
+pub mod fetch;
+pub mod cache;
+pub mod emoji;
+pub mod markup;
+
+pub use template::{Template, Feature, FeatureType, Alignment, VerticalAlignment, Pivot, NamedPivot,
+                   Fit, Anchor, Crop, Rendered, RenderOptions, OutputFormat};
+pub use parse::{parse_text, parse_arguments, Arguments, ParseError, ParseErrorKind};
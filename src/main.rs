@@ -1,32 +1,26 @@
+extern crate meme_bot;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate toml;
 extern crate image;
-extern crate imageproc;
-extern crate rusttype;
-extern crate textwrap;
 #[macro_use]
 extern crate serenity;
 #[macro_use]
 extern crate lazy_static;
 extern crate time;
-extern crate hyper;
-extern crate url;
 extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
-extern crate hyper_native_tls;
 extern crate regex;
 extern crate rand;
 
-mod template;
-mod parse;
-mod imageutil;
 mod config;
+mod cli;
 
-use template::Template;
+use meme_bot::{emoji, parse, Template, FeatureType, RenderOptions};
+use meme_bot::template::{get_template, load_templates};
 
 use config::Config;
 
@@ -35,7 +29,8 @@ use lazy_static::LazyStatic;
 use regex::Regex;
 use rand::Rng;
 
-use std::fs;
+use std::env;
+use std::process;
 use std::collections::HashMap;
 use std::sync::RwLock;
 use std::sync::Arc;
@@ -69,6 +64,15 @@ impl client::EventHandler for Handler {
 }
 fn main() {
     env_logger::init().unwrap();
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    if args.first().map(|x| x.as_str()) == Some("render") {
+        //render straight to a file instead of running the bot
+        if let Err(e) = cli::render(&args[1..]) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
     info!("Loading config...");
     match Config::load_from("config.toml") {
         Err(e) => {
@@ -81,16 +85,23 @@ fn main() {
                 let mut conf = CONFIG.write().unwrap();
                 token = Some(config.token.clone());
                 prefixes = Some(config.prefixes.clone());
+                if let Err(e) = meme_bot::fetch::configure(config.fetch.clone()) {
+                    error!("Error while loading config: {}", e);
+                    return;
+                }
                 *conf = config;
             }
             info!("Loading templates...");
             LazyStatic::initialize(&TEMPLATES);
-            match load_templates() {
+            match load_templates("./templates") {
                 Ok(templates) => {
                     {
                         let mut cache = TEMPLATES.write().unwrap();
                         *cache = templates;
                     }
+                    if emoji::available() == 0 {
+                        warn!("No emoji set in {}, so emoji will be drawn as text. See the README to install one.", emoji::EMOJI_DIR);
+                    }
                     info!("Logging in...");
                     let mut client = Client::new(token.unwrap().as_str(), Handler {});
                     client.with_framework(move |f| {
@@ -138,29 +149,6 @@ fn main() {
         }
     }
 }
-fn get_template<'a>(templates: &'a Vec<Template>, name: &str) -> Option<&'a Template> {
-    templates.iter().find(|template| {
-        template.short_name == name || template.aliases.contains(&name.to_owned())
-    })
-}
-fn load_templates() -> Result<Vec<Template>, (Option<String>, template::Error)> {
-    let mut templates = Vec::new();
-    let files = fs::read_dir("./templates").map_err(|e| {
-        (None, template::Error::Io(e))
-    })?;
-    for file in files {
-        let path = file.map_err(|e| (None, template::Error::Io(e)))?.path();
-        match path.extension().map(|e| e.to_str().unwrap_or("")) {
-            Some("toml") => {
-                templates.push(Template::from_file(path.as_path()).map_err(|e| {
-                    (Some(path.to_str().unwrap().to_owned()), e)
-                })?);
-            }
-            _ => {}
-        }
-    }
-    Ok(templates)
-}
 fn list_templates() -> String {
     TEMPLATES
         .read()
@@ -170,57 +158,242 @@ fn list_templates() -> String {
         .collect::<Vec<String>>()
         .join(", ")
 }
+///Image URLs posted along with a message: attachments first, then embedded images, in the order
+///they appear. Embeds of links that were given as `arguments` are skipped, since those links are
+///already used where they were given. (Stickers aren't available through this version of serenity.)
+fn message_images(message: &Message, arguments: &[String]) -> Vec<String> {
+    let attachments = message
+        .attachments
+        .iter()
+        .map(|x| (x.filename.as_str(), x.url.as_str()))
+        .collect::<Vec<(&str, &str)>>();
+    let embeds = message
+        .embeds
+        .iter()
+        .filter_map(|embed| {
+            let image = match (&embed.image, &embed.thumbnail) {
+                (&Some(ref image), _) => image.url.as_str(),
+                (&None, &Some(ref thumbnail)) => thumbnail.url.as_str(),
+                (&None, &None) => return None,
+            };
+            Some((embed.url.as_ref().map(|x| x.as_str()), image))
+        })
+        .collect::<Vec<(Option<&str>, &str)>>();
+    pick_images(&attachments, &embeds, arguments)
+}
+///File extensions of the attachments that are used as images.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp", "bmp"];
+///Picks the image URLs out of a message's attachments (file name and URL) and embeds (the link
+///that was embedded, if any, and the image shown for it).
+fn pick_images(
+    attachments: &[(&str, &str)],
+    embeds: &[(Option<&str>, &str)],
+    arguments: &[String],
+) -> Vec<String> {
+    let mut urls = Vec::new();
+    for &(filename, url) in attachments {
+        //videos have dimensions too, so go by the file type
+        let extension = filename.rsplit('.').next().unwrap_or("").to_lowercase();
+        if filename.contains('.') && IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+            urls.push(url.to_owned());
+        }
+    }
+    for &(link, image) in embeds {
+        let given = |url: &str| arguments.iter().any(|x| x.contains(url));
+        if !link.map_or(false, &given) && !given(image) {
+            urls.push(image.to_owned());
+        }
+    }
+    urls
+}
+///Serenity only splits arguments on spaces, so a new line (shift-enter) right after the template
+///name leaves the name stuck to the first argument. This splits it back off.
+fn split_template_name(mut args: Vec<String>) -> Vec<String> {
+    let split = match args.first() {
+        Some(first) => first.find('\n').map(|index| {
+            (first[..index].trim().to_owned(), first[index + 1..].to_owned())
+        }),
+        None => None,
+    };
+    if let Some((name, rest)) = split {
+        args[0] = name;
+        if !rest.is_empty() {
+            args.insert(1, rest);
+        }
+    }
+    args
+}
+///Fills the image features that weren't given an argument with `images`, in order. Text features
+///in between are left blank.
+fn fill_images(texts: &mut Vec<String>, template: &Template, images: Vec<String>) {
+    let given = texts.len();
+    let mut images = images.into_iter();
+    for (index, feature) in template.features.iter().enumerate() {
+        if index >= texts.len() {
+            texts.push(String::new());
+        }
+        if texts[index].is_empty() && feature.kind != FeatureType::Text {
+            match images.next() {
+                Some(url) => texts[index] = url,
+                None => break,
+            }
+        }
+    }
+    //don't leave blank features at the end
+    while texts.len() > given && texts.last().map_or(false, |x| x.is_empty()) {
+        texts.pop();
+    }
+}
+///How many messages back `^` looks for images.
+const HISTORY_LIMIT: u64 = 50;
+///Replaces arguments that stand for images with the images' URLs: `<@id>` mentions become that
+///user's avatar, custom emoji become their picture, and `^` (or `^N`) becomes the last (or Nth
+///last) image posted in the channel. Emoji and `^` are only replaced in arguments for image
+///features, so they can still be used as text.
+fn substitute_images(
+    message: &Message,
+    template: &Template,
+    texts: &mut Vec<String>,
+) -> Result<(), String> {
+    let mention_regex = Regex::new("^<@!?([0-9]+)>$").unwrap();
+    let emoji_regex = Regex::new("^<(a?):[A-Za-z0-9_]+:([0-9]+)>$").unwrap();
+    let previous_regex = Regex::new("^\\^([0-9]*)$").unwrap();
+    //the channel's history is only looked up if it's needed
+    let mut history: Option<Vec<String>> = None;
+    let mut replacements: Vec<(usize, String)> = Vec::new();
+    for (text_index, text) in texts.iter().enumerate() {
+        let takes_images = template
+            .features
+            .get(text_index)
+            .map_or(false, |x| x.kind != FeatureType::Text);
+        let (emoji, previous) = if takes_images {
+            (emoji_regex.captures(text), previous_regex.captures(text))
+        } else {
+            (None, None)
+        };
+        if let Some(captures) = mention_regex.captures(text) {
+            if let Ok(id) = captures.get(1).unwrap().as_str().parse::<u64>() {
+                for mention in &message.mentions {
+                    if mention.id == id {
+                        //we've found the user!
+                        let avatar_url = if let Some(url) = mention.avatar_url() {url} else {mention.default_avatar_url()};
+                        let avatar_url = avatar_url.replace(".webp", ".png"); //hacky, but image doesn't support webp properly
+                        replacements.push((text_index, avatar_url));
+                    }
+                }
+            }
+        } else if let Some(captures) = emoji {
+            //animated emoji are GIFs
+            let extension = if captures.get(1).unwrap().as_str() == "a" {"gif"} else {"png"};
+            let id = captures.get(2).unwrap().as_str();
+            replacements.push((text_index, format!("https://cdn.discordapp.com/emojis/{}.{}", id, extension)));
+        } else if let Some(captures) = previous {
+            let n = match previous_index(captures.get(1).unwrap().as_str()) {
+                Some(n) => n,
+                None => {
+                    return Err(format!("`{}` isn't a valid image number. Use `^` for the last image, `^2` for the one before it, and so on.", text));
+                }
+            };
+            if history.is_none() {
+                history = Some(channel_images(message)?);
+            }
+            match history.as_ref().unwrap().get(n - 1) {
+                Some(url) => replacements.push((text_index, url.clone())),
+                None => {
+                    return Err(format!("Couldn't find image `{}` in the last {} messages.", text, HISTORY_LIMIT));
+                }
+            }
+        }
+    }
+    for (index, url) in replacements {
+        texts[index] = url;
+    }
+    Ok(())
+}
+///Which image back the digits after a `^` point to, counting from 1. Plain `^` is 1, and `^0` or
+///a number too big to count to isn't valid.
+fn previous_index(digits: &str) -> Option<usize> {
+    if digits.is_empty() {
+        return Some(1);
+    }
+    match digits.parse::<usize>() {
+        Ok(0) | Err(_) => None,
+        Ok(n) => Some(n),
+    }
+}
+///Every image posted in `message`'s channel in the HISTORY_LIMIT messages before it, newest first.
+///Fails if the history can't be read, which usually means a missing permission.
+fn channel_images(message: &Message) -> Result<Vec<String>, String> {
+    match message.channel_id.messages(|g| g.before(message.id).limit(HISTORY_LIMIT)) {
+        Ok(mut messages) => {
+            //newest first, whatever order the API returns them in
+            messages.sort_by(|a, b| b.id.cmp(&a.id));
+            Ok(messages.iter().flat_map(|x| message_images(x, &[])).collect())
+        }
+        Err(e) => {
+            warn!("Error fetching channel history: {}", e);
+            Err("Couldn't read this channel's history to find earlier images. Make sure I have the Read Message History permission here.".to_owned())
+        }
+    }
+}
 command!(meme(_ctx, message, args) {
+    let args = split_template_name(args);
+    let images = message_images(&message, &args);
     match args.len() {
-        0|1 => {
+        0|1 if args.is_empty() || images.is_empty() => {
             let ref prefix = CONFIG.read().unwrap().prefixes[0];
             let _ = message.reply(format!("**Usage**: `{}meme <template> \"<text1>\" \"[text2]\" ...`\nTemplates you can use: {}\nUse `{}info <template>` for more specific information.", prefix, list_templates(), prefix).as_str());
         }
         _ => {
             let ref template_name = args[0];
-            let templates = TEMPLATES.read().unwrap();
-            let template = get_template(&templates, template_name.as_str());
+            //copy the template out, so the lock isn't held while images download
+            let template = get_template(&TEMPLATES.read().unwrap(), template_name.as_str()).cloned();
             if let Some(template) = template {
                 let texts = args.iter().skip(1).map(|x| x.as_str()).collect::<Vec<&str>>();
-                match parse::parse_text(texts.as_slice()) {
-                    Ok(mut texts) => {
-                            let mention_regex = Regex::new("^<@!?([0-9]+)>$").unwrap();
-                            let mut replacements: Vec<(usize, &serenity::model::User)> = Vec::new();
-                            for (text_index, text) in texts.iter().enumerate() {
-                                if let Some(captures) = mention_regex.captures(text) {
-                                    if let Ok(id) = captures.get(1).unwrap().as_str().parse::<u64>() {
-                                        for mention in &message.mentions {
-                                            if mention.id == id {
-                                                //we've found the user!
-                                                replacements.push((text_index, mention));
+                //keyed arguments like caption="text" are lined up with the features by id, and
+                //flags like --gif become render options
+                let input = texts.join(" ");
+                let parsed = parse::parse_arguments(texts.as_slice())
+                    //point at where in the input the mistake is
+                    .map_err(|e| format!("{}\n{}", e, e.caret(input.as_str())))
+                    .and_then(|arguments| match template.arrange(&arguments) {
+                        Ok(texts) => Ok((texts, arguments.options)),
+                        Err(e) => Err(e.to_string()),
+                    });
+                match parsed {
+                    Ok((mut texts, options)) => {
+                            if let Err(e) = substitute_images(&message, &template, &mut texts) {
+                                let _ = message.reply(e.as_str());
+                            } else {
+                                fill_images(&mut texts, &template, images);
+                                match template.render(texts.iter().map(|x| x.as_str()).collect::<Vec<&str>>().as_slice(), &options) {
+                                    Ok(meme) => {
+                                        let encoded = match options.format {
+                                            Some(format) => meme.encode_as(format),
+                                            None => meme.encode(),
+                                        };
+                                        match encoded {
+                                            Ok((buf, extension)) => {
+                                                let filename = format!("meme.{}", extension);
+                                                let _ = message.channel_id.send_files(vec![(buf.as_slice(), filename.as_str())], |m|
+                                                                                      m.content(
+                                                                                          format!("**{}**", template.name).as_str()));
+                                            }
+                                            Err(e) => {
+                                                warn!("Error encoding: {}", e);
+                                                let _ = message.reply(e.to_string().as_str());
                                             }
                                         }
+                                    },
+                                    Err(e) => {
+                                        warn!("Error rendering: {}", e);
+                                        let _ = message.reply(e.to_string().as_str());
                                     }
                                 }
                             }
-                            for (index, user) in replacements {
-                                //replace the mention with avatar url
-                                let _ = texts.remove(index);
-                                let avatar_url = if let Some(url) = user.avatar_url() {url} else {user.default_avatar_url()};
-                                let avatar_url = avatar_url.replace(".webp", ".png"); //hacky, but image doesn't support webp properly
-                                texts.insert(index, avatar_url); //insert it back
-                            }
-                            match template.render(texts.iter().map(|x| x.as_str()).collect::<Vec<&str>>().as_slice(), false) {
-                                Ok(image) => {
-                                    let mut buf: Vec<u8> = Vec::new();
-                                    let _ = image.save(&mut buf, image::ImageFormat::PNG);
-                                    let _ = message.channel_id.send_files(vec![(buf.as_slice(), "meme.png")], |m|
-                                                                          m.content(
-                                                                              format!("**{}**", template.name).as_str()));
-                                },
-                                Err(e) => {
-                                    warn!("Error rendering: {}", e);
-                                    let _ = message.reply(e.to_string().as_str());
-                                }
-                            }
                     }
                     Err(e) => {
-                        let _ = message.reply(format!("Error parsing your input: {}", e.to_string()).as_str());
+                        let _ = message.reply(format!("Error parsing your input: {}", e).as_str());
                     }
                 }
             } else {
@@ -239,6 +412,14 @@ const TIPS: &[&'static str] = &[
     "Text with spaces in it needs to be escaped with quotes (\"). If your argument is a url, or the text is only one word, then leave the quotes out!",
     "If you want quotes inside your meme, escape them with a backslash (\\\\\"). If you want to use a backslash, just escape it with another one!",
     "Both double quotes (\") and single quotes (\') can be used to have spaces in text. Since only the outermost kind of quote is recognized, single quotes can be used unescaped inside of double quotes and vice-versa.",
+    "Curly quotes (“like this”) from phone keyboards work just like regular ones.",
+    "Captions understand Discord formatting: `**bold**`, `*italic*`, `~~strikethrough~~`, and even colors with `||color:red||red text||`!",
+    "Need a line break in a caption? Press shift-enter inside the quotes, or type \\\\n.",
+    "Use `^` in place of an image to use the last image posted in the channel, or `^2`, `^3` and so on to go further back.",
+    "Custom emoji and regular emoji work as images too. Just put one in place of an image link.",
+    "Give a feature its argument by name, like `left=@someone`, to skip the ones before it. `info` lists the names a template has.",
+    "Add `--gif`, `--jpeg`, `--scale 0.5`, `--font <name>`, `--color red` or `--deepfry` to a meme command to change how it comes out.",
+    "You can attach images to your message instead of linking them. They fill the template's image slots that you didn't give an argument, in order.",
     "If you insta-pick Jungle Legion, you're trash.",
 ];
 command!(tip(_ctx, message) {
@@ -251,15 +432,15 @@ command!(info(_ctx, message, args) {
             let _ = message.reply("Provide the name of the meme you want more information about.");
         }
         _ => {
-            let ref template = args[0];
-            if let Some(template) = get_template(&TEMPLATES.read().unwrap(), template.as_str()) {
+            let ref template_name = args[0];
+            let template = get_template(&TEMPLATES.read().unwrap(), template_name.as_str()).cloned();
+            if let Some(template) = template {
                 let mut texts = Vec::new();
                 for i in 0..template.features.len() {
                     texts.push(format!("Text {}", i+1));
                 }
                 let mut example_usage = format!("{}meme {} ", CONFIG.read().unwrap().prefixes[0], template.short_name);
                 for feature in &template.features {
-                    use template::FeatureType;
                     match feature.kind {
                         FeatureType::Image => {
                             example_usage += "\"<image>\" ";
@@ -272,10 +453,21 @@ command!(info(_ctx, message, args) {
                         }
                     }
                 }
-                let image = template.render(texts.iter().map(|x| x.as_str()).collect::<Vec<&str>>().as_slice(), true).unwrap();
-                let mut buf = Vec::new();
-                let _ = image.save(&mut buf, image::ImageFormat::PNG);
-                let filename = "meme.png";
+                let options = RenderOptions { show_rectangles: true, ..RenderOptions::default() };
+                let encoded = template
+                    .render(texts.iter().map(|x| x.as_str()).collect::<Vec<&str>>().as_slice(), &options)
+                    .map_err(|e| ("rendering", e))
+                    .and_then(|meme| meme.encode().map_err(|e| ("encoding", e)));
+                let (buf, extension) = match encoded {
+                    Ok(encoded) => encoded,
+                    Err((stage, e)) => {
+                        warn!("Error {}: {}", stage, e);
+                        let _ = message.reply(e.to_string().as_str());
+                        return Ok(());
+                    }
+                };
+                let filename = format!("meme.{}", extension);
+                let filename = filename.as_str();
                 //show info
                 let _ = message.channel_id.send_files(vec![(buf.as_slice(), filename)], |m|
                     m.content(
@@ -283,19 +475,35 @@ command!(info(_ctx, message, args) {
                                 template.name,
                                 template.short_name,
                                 if template.aliases.len() > 0 {template.aliases.join(", ")} else {"None".to_owned()},
-                                template.features.len(), 
+                                feature_list(&template), 
                                 example_usage)
                         .as_str()
                     ));
             } else {
-                let _ = message.reply(format!("Template `{}` not found. Use `{}list` for a list of templates.", template, CONFIG.read().unwrap().prefixes[0]).as_str());
+                let _ = message.reply(format!("Template `{}` not found. Use `{}list` for a list of templates.", template_name, CONFIG.read().unwrap().prefixes[0]).as_str());
             }
         }
     }
 });
+///The number of features a template has, along with the names of those that have one.
+fn feature_list(template: &Template) -> String {
+    let ids = template
+        .features
+        .iter()
+        .filter_map(|x| x.id.as_ref().map(|id| format!("`{}`", id)))
+        .collect::<Vec<String>>();
+    if ids.is_empty() {
+        template.features.len().to_string()
+    } else {
+        format!("{} ({})", template.features.len(), ids.join(", "))
+    }
+}
+///Read Messages, Send Messages, Attach Files and Read Message History (for `^`).
+const INVITE_PERMISSIONS: u64 = 1024 | 2048 | 32768 | 65536;
 fn invite_url(id: UserId) -> String {
     format!(
-        "https://discordapp.com/oauth2/authorize?permissions=35840&scope=bot&client_id={}",
+        "https://discordapp.com/oauth2/authorize?permissions={}&scope=bot&client_id={}",
+        INVITE_PERMISSIONS,
         id
     )
 }
@@ -402,3 +610,77 @@ fn help(
     }
     Ok(())
 }
+#[cfg(test)]
+mod test {
+    use meme_bot::Template;
+
+    use toml;
+
+    use {fill_images, pick_images, previous_index};
+
+    ///A template with one feature of each of `kinds`.
+    fn template(kinds: &[&str]) -> Template {
+        let features = kinds
+            .iter()
+            .map(|kind| {
+                format!(
+                    "[[features]]\nkind = \"{}\"\nx = 0\ny = 0\nw = 10\nh = 10\nfont_size = 10.0\n",
+                    kind
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        toml::from_str(
+            format!("image = \"a.png\"\nname = \"a\"\nshort_name = \"a\"\n{}", features).as_str(),
+        ).unwrap()
+    }
+    fn strings(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn picks_attached_and_embedded_images() {
+        let attachments = [
+            ("cat.PNG", "https://cdn/cat.PNG"),
+            ("clip.mp4", "https://cdn/clip.mp4"),
+            ("clip.webm", "https://cdn/clip.webm"),
+            ("notes", "https://cdn/notes"),
+            ("dog.jpg", "https://cdn/dog.jpg"),
+        ];
+        let embeds = [
+            (Some("https://site/page"), "https://site/preview.png"),
+            (None, "https://site/given.gif"),
+            (Some("https://other/page"), "https://other/preview.png"),
+        ];
+        //the first link was passed as an argument, and the second image too
+        let arguments = strings(&["rare", "\"https://site/page\"", "https://site/given.gif"]);
+        assert_eq!(
+            pick_images(&attachments, &embeds, &arguments),
+            strings(&["https://cdn/cat.PNG", "https://cdn/dog.jpg", "https://other/preview.png"])
+        );
+    }
+    #[test]
+    fn fills_image_features_in_order() {
+        let template = template(&["Text", "Image", "Either", "Image"]);
+        let images = strings(&["first", "second", "third"]);
+        //the Either feature already has text, so images go to the two Image features
+        let mut texts = strings(&["top", "", "caption"]);
+        fill_images(&mut texts, &template, images.clone());
+        assert_eq!(texts, strings(&["top", "first", "caption", "second"]));
+        //blank features take images in order, and the text feature stays blank
+        let mut texts = Vec::new();
+        fill_images(&mut texts, &template, images.clone());
+        assert_eq!(texts, strings(&["", "first", "second", "third"]));
+        //with fewer images than features, trailing blanks are dropped
+        let mut texts = strings(&["top"]);
+        fill_images(&mut texts, &template, strings(&["only"]));
+        assert_eq!(texts, strings(&["top", "only"]));
+    }
+    #[test]
+    fn numbers_previous_images() {
+        assert_eq!(previous_index(""), Some(1));
+        assert_eq!(previous_index("3"), Some(3));
+        assert_eq!(previous_index("0"), None);
+        assert_eq!(previous_index("99999999999999999999999"), None);
+    }
+}
@@ -0,0 +1,246 @@
+///Markup module: Discord-style formatting in captions, like `**bold**`, `*italic*`, `~~strike~~`
+///and `||color:red||colored text||`, turned into runs of text that share a style.
+
+use parse::parse_color;
+
+///Characters that a backslash makes plain text instead of markup.
+const ESCAPABLE: &str = "*~|";
+
+///How a run of text is drawn.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub strike: bool,
+    ///Overrides the feature's font color.
+    pub color: Option<[u8; 4]>,
+}
+
+///A piece of text drawn all in one style.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    pub text: String,
+    pub style: Style,
+}
+impl Run {
+    ///Text without any styling.
+    pub fn plain(text: &str) -> Run {
+        Run {
+            text: text.to_owned(),
+            style: Style::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Bold,
+    Italic,
+    Strike,
+    Color([u8; 4]),
+    EndColor,
+}
+///A piece of the input: plain text or a marker, along with the text it was made from.
+struct Token<'a> {
+    kind: Kind,
+    raw: &'a str,
+}
+
+///Splits `text` into styled runs. Markers are only used when they're closed, so a lone `*` (or
+///one escaped as `\*`) stays plain text. Colors are closed by the next `||` and can be nested.
+pub fn parse(text: &str) -> Vec<Run> {
+    let tokens = tokenize(text);
+    let mut matched = vec![false; tokens.len()];
+    for kind in &[Kind::Bold, Kind::Italic, Kind::Strike] {
+        //the same marker opens and closes, so they pair up in order
+        let positions = tokens
+            .iter()
+            .enumerate()
+            .filter(|&(_, token)| token.kind == *kind)
+            .map(|(index, _)| index)
+            .collect::<Vec<usize>>();
+        for pair in positions.chunks(2).filter(|pair| pair.len() == 2) {
+            matched[pair[0]] = true;
+            matched[pair[1]] = true;
+        }
+    }
+    let mut open = Vec::new();
+    for (index, token) in tokens.iter().enumerate() {
+        match token.kind {
+            Kind::Color(_) => open.push(index),
+            Kind::EndColor => {
+                if let Some(start) = open.pop() {
+                    matched[start] = true;
+                    matched[index] = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut runs = Vec::new();
+    let mut style = Style::default();
+    let mut colors = Vec::new();
+    for (index, token) in tokens.iter().enumerate() {
+        if token.kind == Kind::Text || !matched[index] {
+            push_text(&mut runs, token.raw, style);
+            continue;
+        }
+        match token.kind {
+            Kind::Bold => style.bold = !style.bold,
+            Kind::Italic => style.italic = !style.italic,
+            Kind::Strike => style.strike = !style.strike,
+            Kind::Color(color) => {
+                colors.push(color);
+                style.color = Some(color);
+            }
+            Kind::EndColor => {
+                colors.pop();
+                style.color = colors.last().cloned();
+            }
+            Kind::Text => {}
+        }
+    }
+    runs
+}
+///The text of `runs` without any of the styling.
+pub fn plain_text(runs: &[Run]) -> String {
+    runs.iter().map(|run| run.text.as_str()).collect()
+}
+///Adds `text` to the end of `runs`, joining it onto the last run if that has the same style.
+fn push_text(runs: &mut Vec<Run>, text: &str, style: Style) {
+    if text.is_empty() {
+        return;
+    }
+    if let Some(last) = runs.last_mut() {
+        if last.style == style {
+            last.text.push_str(text);
+            return;
+        }
+    }
+    runs.push(Run {
+        text: text.to_owned(),
+        style: style,
+    });
+}
+fn tokenize<'a>(text: &'a str) -> Vec<Token<'a>> {
+    let mut tokens = Vec::new();
+    //where the plain text since the last marker starts
+    let mut start = 0;
+    let mut index = 0;
+    while index < text.len() {
+        let rest = &text[index..];
+        let escaped = rest.starts_with('\\') &&
+            rest[1..].chars().next().map_or(false, |c| ESCAPABLE.contains(c));
+        //the kind of marker, where its text starts and how long it is
+        let found = if escaped {
+            //the escaped character is kept as text, without the backslash
+            Some((Kind::Text, index + 1, 2))
+        } else if rest.starts_with("**") {
+            Some((Kind::Bold, index, 2))
+        } else if rest.starts_with('*') {
+            Some((Kind::Italic, index, 1))
+        } else if rest.starts_with("~~") {
+            Some((Kind::Strike, index, 2))
+        } else if rest.starts_with("||") {
+            let (kind, length) = color_marker(rest);
+            Some((kind, index, length))
+        } else {
+            None
+        };
+        match found {
+            Some((kind, raw_start, length)) => {
+                if start < index {
+                    tokens.push(Token {
+                        kind: Kind::Text,
+                        raw: &text[start..index],
+                    });
+                }
+                tokens.push(Token {
+                    kind: kind,
+                    raw: &text[raw_start..index + length],
+                });
+                index += length;
+                start = index;
+            }
+            None => index += rest.chars().next().unwrap().len_utf8(),
+        }
+    }
+    if start < text.len() {
+        tokens.push(Token {
+            kind: Kind::Text,
+            raw: &text[start..],
+        });
+    }
+    tokens
+}
+///Reads the marker at the start of `text`, which starts with `||`: either a color like
+///`||color:red||`, or a bare `||` that ends one. Returns it along with its length.
+fn color_marker(text: &str) -> (Kind, usize) {
+    let inner = &text[2..];
+    let prefix = ["color:", "colour:"]
+        .iter()
+        .find(|prefix| inner.starts_with(*prefix))
+        .map_or(0, |prefix| prefix.len());
+    if prefix > 0 {
+        if let Some(end) = inner.find("||") {
+            if let Some(color) = parse_color(inner[prefix..end].trim()) {
+                return (Kind::Color(color), end + 4);
+            }
+        }
+    }
+    (Kind::EndColor, 2)
+}
+#[cfg(test)]
+mod test {
+    use markup::{parse, plain_text, Run, Style};
+
+    fn run(text: &str, bold: bool, italic: bool, strike: bool, color: Option<[u8; 4]>) -> Run {
+        Run {
+            text: text.to_owned(),
+            style: Style {
+                bold: bold,
+                italic: italic,
+                strike: strike,
+                color: color,
+            },
+        }
+    }
+    #[test]
+    fn styles_runs() {
+        assert_eq!(
+            parse("a **bold** and *italic* ~~gone~~"),
+            vec![
+                run("a ", false, false, false, None),
+                run("bold", true, false, false, None),
+                run(" and ", false, false, false, None),
+                run("italic", false, true, false, None),
+                run(" ", false, false, false, None),
+                run("gone", false, false, true, None),
+            ]
+        );
+        assert_eq!(
+            parse("***both***"),
+            vec![run("both", true, true, false, None)]
+        );
+        let red = Some([255, 0, 0, 255]);
+        let blue = Some([0, 0, 255, 255]);
+        assert_eq!(
+            parse("||color:red||red ||colour:#00f||blue|| red|| plain"),
+            vec![
+                run("red ", false, false, false, red),
+                run("blue", false, false, false, blue),
+                run(" red", false, false, false, red),
+                run(" plain", false, false, false, None),
+            ]
+        );
+    }
+    #[test]
+    fn unclosed_markers_are_text() {
+        assert_eq!(parse("5 * 3 = 15"), vec![Run::plain("5 * 3 = 15")]);
+        assert_eq!(parse("**half"), vec![Run::plain("**half")]);
+        assert_eq!(parse("\\*not italic\\*"), vec![Run::plain("*not italic*")]);
+        assert_eq!(parse("||color:notacolor||x||"), vec![Run::plain("||color:notacolor||x||")]);
+        assert_eq!(plain_text(&parse("**a** b")), "a b");
+    }
+}
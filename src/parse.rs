@@ -1,62 +1,354 @@
-pub fn parse_text(input: &[&str]) -> Result<Vec<String>, String> {
+use std::error;
+use std::fmt;
+
+use template::{OutputFormat, RenderOptions};
+
+///The most a meme can be scaled up by with `--scale`.
+const MAX_SCALE: f32 = 4.0;
+
+///Arguments to a template, split into the ones given in order and the ones given to a feature by
+///its id, like `caption="ten spiders"`, along with any `--flags`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Arguments {
+    pub positional: Vec<String>,
+    ///Keyed arguments as (key, value, the number of positional arguments before it). The position
+    ///puts the word back in order if the template has no feature with that id.
+    pub keyed: Vec<(String, String, usize)>,
+    pub options: RenderOptions,
+}
+
+///One word of the input.
+struct Token {
+    ///The id before an unquoted `=`, if there was one.
+    key: Option<String>,
+    text: String,
+    ///Whether any of the word was in quotes. Quoted words are never keys or flags.
+    quoted: bool,
+    ///Where the word starts in the input, in bytes.
+    offset: usize,
+}
+impl Token {
+    fn new(offset: usize) -> Token {
+        Token {
+            key: None,
+            text: String::new(),
+            quoted: false,
+            offset: offset,
+        }
+    }
+}
+
+///What went wrong while parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    ///A quote that's never closed.
+    UnbalancedQuote,
+    ///A backslash before a character that can't be escaped.
+    InvalidEscape(char),
+    ///A backslash at the very end of the input, with nothing to escape.
+    TrailingEscape,
+    ///An option that doesn't exist, like `--what`.
+    UnknownOption(String),
+    ///An option that needs a value but wasn't given one.
+    MissingValue(String),
+    ///An option given a value it can't use, as (option, value, reason).
+    InvalidValue(String, String, String),
+}
+///An error in the input, along with where in the input (joined by spaces) it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    ///The offset of the offending character or word, in bytes.
+    pub offset: usize,
+    pub kind: ParseErrorKind,
+}
+impl ParseError {
+    fn new(offset: usize, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            offset: offset,
+            kind: kind,
+        }
+    }
+    ///Shows the line of `input` the error is on, with a caret under the offending spot. It's
+    ///wrapped in a code block so the caret lines up in Discord.
+    pub fn caret(&self, input: &str) -> String {
+        let offset = (0..self.offset + 1)
+            .rev()
+            .find(|&x| input.is_char_boundary(x))
+            .unwrap_or(0);
+        let start = input[..offset].rfind('\n').map_or(0, |x| x + 1);
+        let end = input[offset..].find('\n').map_or(input.len(), |x| offset + x);
+        let column = input[start..offset].chars().count();
+        format!(
+            "```\n{}\n{}^\n```",
+            input[start..end].replace("```", "`\u{200b}``"),
+            " ".repeat(column)
+        )
+    }
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::UnbalancedQuote => write!(f, "This quote is never closed"),
+            ParseErrorKind::InvalidEscape(c) => {
+                write!(f, "\\{} isn't an escape. Use \\\\, \\\", \\', \\n, \\*, \\~ or \\|", c)
+            }
+            ParseErrorKind::TrailingEscape => write!(f, "There's nothing after this \\ to escape"),
+            ParseErrorKind::UnknownOption(ref name) => write!(f, "Unknown option --{}", name),
+            ParseErrorKind::MissingValue(ref name) => write!(f, "--{} needs a value", name),
+            ParseErrorKind::InvalidValue(ref name, ref value, ref reason) => {
+                write!(f, "{} isn't a valid --{}: {}", value, name, reason)
+            }
+        }
+    }
+}
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        match self.kind {
+            ParseErrorKind::UnbalancedQuote => "unbalanced quote",
+            ParseErrorKind::InvalidEscape(_) => "invalid escape",
+            ParseErrorKind::TrailingEscape => "trailing escape",
+            ParseErrorKind::UnknownOption(_) => "unknown option",
+            ParseErrorKind::MissingValue(_) => "missing value",
+            ParseErrorKind::InvalidValue(..) => "invalid value",
+        }
+    }
+}
+
+///Splits the input into texts, treating `id=value` and `--flags` like any other text.
+pub fn parse_text(input: &[&str]) -> Result<Vec<String>, ParseError> {
+    Ok(
+        tokenize(input)?
+            .into_iter()
+            .map(|token| match token.key {
+                Some(key) => format!("{}={}", key, token.text),
+                None => token.text,
+            })
+            .collect(),
+    )
+}
+///Splits the input into positional and keyed arguments and render options. A word is keyed if it
+///starts with an unquoted id made of letters, digits and underscores, followed by `=`. Unquoted
+///words starting with `--` are options, like `--gif` or `--scale 0.5`.
+pub fn parse_arguments(input: &[&str]) -> Result<Arguments, ParseError> {
+    let mut arguments = Arguments::default();
+    let mut tokens = tokenize(input)?.into_iter();
+    while let Some(token) = tokens.next() {
+        match token.key {
+            Some(key) => {
+                let position = arguments.positional.len();
+                arguments.keyed.push((key, token.text, position));
+            }
+            None if !token.quoted && token.text.starts_with("--") => {
+                parse_option(&token, &mut tokens, &mut arguments.options)?;
+            }
+            None => arguments.positional.push(token.text),
+        }
+    }
+    Ok(arguments)
+}
+///Applies the option `flag` to `options`, taking its value from the flag itself (`--scale=0.5`)
+///or the next token (`--scale 0.5`).
+fn parse_option<I: Iterator<Item = Token>>(
+    flag: &Token,
+    tokens: &mut I,
+    options: &mut RenderOptions,
+) -> Result<(), ParseError> {
+    let text = flag.text.as_str();
+    let (name, inline) = match text.find('=') {
+        Some(index) => (&text[2..index], Some(text[index + 1..].to_owned())),
+        None => (&text[2..], None),
+    };
+    let offset = flag.offset;
+    let mut value = || {
+        inline
+            .clone()
+            .or_else(|| tokens.next().map(|x| x.text))
+            .ok_or_else(|| {
+                ParseError::new(offset, ParseErrorKind::MissingValue(name.to_owned()))
+            })
+    };
+    let invalid = |value: &str, reason: String| {
+        ParseError::new(
+            offset,
+            ParseErrorKind::InvalidValue(name.to_owned(), value.to_owned(), reason),
+        )
+    };
+    match name {
+        "gif" => options.format = Some(OutputFormat::Gif),
+        "jpeg" | "jpg" => options.format = Some(OutputFormat::Jpeg),
+        "png" => options.format = Some(OutputFormat::Png),
+        "deepfry" => options.deepfry = true,
+        "scale" => {
+            let value = value()?;
+            let scale = value.parse::<f32>().map_err(|_| {
+                invalid(&value, "it isn't a number".to_owned())
+            })?;
+            if !(scale > 0.0 && scale <= MAX_SCALE) {
+                return Err(invalid(
+                    &value,
+                    format!("it must be above 0 and at most {}", MAX_SCALE),
+                ));
+            }
+            options.scale = Some(scale);
+        }
+        "font" => options.font = Some(value()?),
+        "color" | "colour" => {
+            let value = value()?;
+            options.color = Some(parse_color(value.as_str()).ok_or_else(|| {
+                invalid(&value, "it isn't a color name or hex code".to_owned())
+            })?);
+        }
+        _ => {
+            return Err(ParseError::new(
+                offset,
+                ParseErrorKind::UnknownOption(name.to_owned()),
+            ))
+        }
+    }
+    Ok(())
+}
+///Parses a color name (like `red`) or hex code (like `#ff0000` or `#f00`).
+pub fn parse_color(color: &str) -> Option<[u8; 4]> {
+    let named = match color.to_lowercase().as_str() {
+        "black" => Some([0, 0, 0, 255]),
+        "white" => Some([255, 255, 255, 255]),
+        "red" => Some([255, 0, 0, 255]),
+        "green" => Some([0, 128, 0, 255]),
+        "lime" => Some([0, 255, 0, 255]),
+        "blue" => Some([0, 0, 255, 255]),
+        "yellow" => Some([255, 255, 0, 255]),
+        "orange" => Some([255, 165, 0, 255]),
+        "purple" => Some([128, 0, 128, 255]),
+        "pink" => Some([255, 192, 203, 255]),
+        "cyan" => Some([0, 255, 255, 255]),
+        "magenta" => Some([255, 0, 255, 255]),
+        "gray" | "grey" => Some([128, 128, 128, 255]),
+        "brown" => Some([139, 69, 19, 255]),
+        _ => None,
+    };
+    if named.is_some() {
+        return named;
+    }
+    let hex = color.trim_left_matches('#');
+    let hex = match hex.len() {
+        //#rgb is short for #rrggbb
+        3 => hex.chars().flat_map(|c| vec![c, c]).collect::<String>(),
+        6 => hex.to_owned(),
+        _ => return None,
+    };
+    if !hex.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    u32::from_str_radix(hex.as_str(), 16).ok().map(|rgb| {
+        [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 255]
+    })
+}
+///Which kind of quote a quoted part of a word is in. Smart quotes from mobile keyboards count as
+///the plain kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quote {
+    Single,
+    Double,
+}
+fn quote_kind(c: char) -> Option<Quote> {
+    match c {
+        '\'' | '\u{2018}' | '\u{2019}' => Some(Quote::Single),
+        '"' | '\u{201c}' | '\u{201d}' => Some(Quote::Double),
+        _ => None,
+    }
+}
+fn tokenize(input: &[&str]) -> Result<Vec<Token>, ParseError> {
     let input = input.join(" ");
-    let mut words = vec![String::new()];
-    let mut single_quote = false;
-    let mut double_quote = false;
-    let mut escape = false;
-    for c in input.chars() {
+    let mut tokens = vec![Token::new(0)];
+    //the quote the input is in, and where it was opened
+    let mut quote: Option<(Quote, usize)> = None;
+    //where the backslash before this character is, if there is one
+    let mut escape: Option<usize> = None;
+    //whether this character follows an unquoted new line, and any whitespace after it
+    let mut line_break = false;
+    for (index, c) in input.char_indices() {
+        if let Some(start) = escape.take() {
+            let escaped = match c {
+                '\\' => '\\',
+                'n' => '\n',
+                //markup characters keep their backslash, so the markup sees them as escaped
+                '*' | '~' | '|' => {
+                    tokens.last_mut().unwrap().text.push('\\');
+                    c
+                }
+                c if quote_kind(c).is_some() => c,
+                c => return Err(ParseError::new(start, ParseErrorKind::InvalidEscape(c))),
+            };
+            tokens.last_mut().unwrap().text.push(escaped);
+            continue;
+        }
+        //a new line and whatever whitespace follows it (like the space serenity joins arguments
+        //with) are all one separator
+        if line_break && c.is_whitespace() {
+            tokens.last_mut().unwrap().offset = index + c.len_utf8();
+            continue;
+        }
+        line_break = false;
         match c {
-            '\'' => {
-                if escape || double_quote {
-                    words.last_mut().unwrap().push('\'');
-                    if escape {
-                        escape = false;
+            '\\' => escape = Some(index),
+            c if quote_kind(c).is_some() => {
+                let kind = quote_kind(c).unwrap();
+                match quote {
+                    //the other kind of quote is just text
+                    Some((open, _)) if open != kind => tokens.last_mut().unwrap().text.push(c),
+                    //inside a quote, ’ is an apostrophe unless it ends the word
+                    Some(_) if c == '\u{2019}' && !ends_word(&input[index + c.len_utf8()..]) => {
+                        tokens.last_mut().unwrap().text.push(c)
                     }
-                } else {
-                    single_quote = !single_quote;
-                }
-            }
-            '"' => {
-                if escape || single_quote {
-                    words.last_mut().unwrap().push('\"');
-                    if escape {
-                        escape = false;
+                    Some(_) => quote = None,
+                    //’ is also how phones type apostrophes, so it doesn't open a quote
+                    None if c == '\u{2019}' => tokens.last_mut().unwrap().text.push(c),
+                    None => {
+                        quote = Some((kind, index));
+                        tokens.last_mut().unwrap().quoted = true;
                     }
-                } else {
-                    double_quote = !double_quote;
                 }
             }
-            '\\' => {
-                if escape {
-                    words.last_mut().unwrap().push('\"');
-                    escape = false;
+            ' ' if quote.is_none() => tokens.push(Token::new(index + 1)),
+            //a new line outside of quotes separates words too, unless a space just before it
+            //already started the next word
+            '\n' | '\r' if quote.is_none() => {
+                if !is_blank(tokens.last().unwrap()) {
+                    tokens.push(Token::new(index + 1));
                 } else {
-                    escape = true;
+                    tokens.last_mut().unwrap().offset = index + 1;
                 }
+                line_break = true;
             }
-            ' ' => {
-                if escape {
-                    return Err("Invalid escape character ' '".to_owned());
-                }
-                if single_quote || double_quote {
-                    words.last_mut().unwrap().push(' ');
-                    escape = false;
-                } else {
-                    words.push(String::new());
-                }
-            }
-            x => {
-                if escape {
-                    return Err(format!("Invalid escape character {}", x));
-                }
-                words.last_mut().unwrap().push(x);
+            '=' if quote.is_none() && is_key(tokens.last().unwrap()) => {
+                //everything before the = names the feature
+                let token = tokens.last_mut().unwrap();
+                token.key = Some(token.text.clone());
+                token.text.clear();
             }
+            x => tokens.last_mut().unwrap().text.push(x),
         }
     }
-    if single_quote || double_quote {
-        return Err("Unbalanced quotes".to_owned());
+    if let Some(start) = escape {
+        return Err(ParseError::new(start, ParseErrorKind::TrailingEscape));
+    }
+    if let Some((_, start)) = quote {
+        return Err(ParseError::new(start, ParseErrorKind::UnbalancedQuote));
     }
-    Ok(words)
+    Ok(tokens)
+}
+///Whether `rest`, the input after a character, starts with whitespace or is empty.
+fn ends_word(rest: &str) -> bool {
+    rest.chars().next().map_or(true, char::is_whitespace)
+}
+///Whether `token` is still empty: no text, no key and no quotes.
+fn is_blank(token: &Token) -> bool {
+    token.key.is_none() && !token.quoted && token.text.is_empty()
+}
+///Whether `token` so far could be a feature id followed by `=`.
+fn is_key(token: &Token) -> bool {
+    token.key.is_none() && !token.quoted && !token.text.is_empty() &&
+        token.text.chars().all(|c| c.is_alphanumeric() || c == '_')
 }
 mod test {
     #[test]
@@ -67,4 +359,130 @@ mod test {
             &[String::from("echo"), String::from("hello world")]
         );
     }
+    #[test]
+    fn keyed_arguments() {
+        use parse::parse_arguments;
+        let arguments = parse_arguments(&[
+            "left=<@1234>",
+            "right_caption=\"ten thousand spiders\"",
+            "\"a=b\"",
+            "https://example.com/a.png?size=128",
+        ]).unwrap();
+        assert_eq!(
+            arguments.keyed,
+            vec![
+                ("left".to_owned(), "<@1234>".to_owned(), 0),
+                ("right_caption".to_owned(), "ten thousand spiders".to_owned(), 0),
+            ]
+        );
+        assert_eq!(
+            arguments.positional,
+            vec!["a=b".to_owned(), "https://example.com/a.png?size=128".to_owned()]
+        );
+    }
+    #[test]
+    fn render_options() {
+        use parse::parse_arguments;
+        use template::OutputFormat;
+        let arguments = parse_arguments(&[
+            "--gif",
+            "--scale",
+            "0.5",
+            "\"hello\"",
+            "--color=#f80",
+            "--font",
+            "impact",
+            "\"--deepfry\"",
+        ]).unwrap();
+        assert_eq!(
+            arguments.positional,
+            vec!["hello".to_owned(), "--deepfry".to_owned()]
+        );
+        assert_eq!(arguments.options.format, Some(OutputFormat::Gif));
+        assert_eq!(arguments.options.scale, Some(0.5));
+        assert_eq!(arguments.options.color, Some([255, 136, 0, 255]));
+        assert_eq!(arguments.options.font, Some("impact".to_owned()));
+        assert!(!arguments.options.deepfry);
+        assert!(parse_arguments(&["--scale", "100"]).is_err());
+        assert!(parse_arguments(&["--color", "notacolor"]).is_err());
+        assert!(parse_arguments(&["--what"]).is_err());
+    }
+    #[test]
+    fn escapes_and_quotes() {
+        use parse::parse_text;
+        assert_eq!(
+            parse_text(&["\"a \\\\ b \\\" c\"", "it\\'s", "one\\ntwo"]).unwrap(),
+            vec!["a \\ b \" c".to_owned(), "it's".to_owned(), "one\ntwo".to_owned()]
+        );
+        //escaped markup is left for the markup to read
+        assert_eq!(parse_text(&["\\*a\\*"]).unwrap(), vec!["\\*a\\*".to_owned()]);
+        //smart quotes work like plain ones, and the other kind is just text inside them
+        assert_eq!(
+            parse_text(&["\u{201c}don\u{2019}t", "panic\u{201d}", "\u{2018}a\"b\u{2019}"])
+                .unwrap(),
+            vec!["don\u{2019}t panic".to_owned(), "a\"b".to_owned()]
+        );
+        assert_eq!(parse_text(&["don\u{2019}t"]).unwrap(), vec!["don\u{2019}t".to_owned()]);
+        //an apostrophe inside a smart quote doesn't close it
+        assert_eq!(
+            parse_text(&["\u{2018}don\u{2019}t\u{2019}", "x"]).unwrap(),
+            vec!["don\u{2019}t".to_owned(), "x".to_owned()]
+        );
+    }
+    #[test]
+    fn new_lines() {
+        use parse::parse_text;
+        //new lines in quotes are kept, and outside of them they separate arguments
+        assert_eq!(
+            parse_text(&["\"top\nbottom\"\n\n'second'\r\nthird", "a\\nb"]).unwrap(),
+            vec![
+                "top\nbottom".to_owned(),
+                "second".to_owned(),
+                "third".to_owned(),
+                "a\nb".to_owned(),
+            ]
+        );
+        //serenity joins arguments with a space, so that can follow a new line too
+        assert_eq!(
+            parse_text(&["top\n", "bottom"]).unwrap(),
+            vec!["top".to_owned(), "bottom".to_owned()]
+        );
+        assert_eq!(
+            parse_text(&["top \n \t\r\n  bottom"]).unwrap(),
+            vec!["top".to_owned(), "bottom".to_owned()]
+        );
+        //quoted, the whitespace is kept
+        assert_eq!(parse_text(&["\"a\n b\""]).unwrap(), vec!["a\n b".to_owned()]);
+    }
+    #[test]
+    fn error_offsets() {
+        use parse::{parse_arguments, parse_text, ParseError, ParseErrorKind};
+        assert_eq!(
+            parse_text(&["hi", "\"there"]),
+            Err(ParseError {
+                offset: 3,
+                kind: ParseErrorKind::UnbalancedQuote,
+            })
+        );
+        assert_eq!(
+            parse_text(&["é\\x"]),
+            Err(ParseError {
+                offset: 2,
+                kind: ParseErrorKind::InvalidEscape('x'),
+            })
+        );
+        assert_eq!(
+            parse_text(&["end\\"]).unwrap_err().kind,
+            ParseErrorKind::TrailingEscape
+        );
+        let error = parse_arguments(&["a", "--what"]).unwrap_err();
+        assert_eq!(error.offset, 2);
+        assert_eq!(error.caret("a --what"), "```\na --what\n  ^\n```");
+        //the caret goes by characters, and only the line with the error is shown
+        let error = ParseError {
+            offset: 5,
+            kind: ParseErrorKind::UnbalancedQuote,
+        };
+        assert_eq!(error.caret("a\né \"b"), "```\né \"b\n  ^\n```");
+    }
 }
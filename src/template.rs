@@ -1,38 +1,51 @@
 use std::path::{Path, PathBuf};
-use std::io::Read;
+use std::io::{Cursor, Read};
+use std::fs;
 use std::fs::File;
 use std::borrow::ToOwned;
 use std::iter;
 use std::f32::consts::PI;
+use std::sync::Arc;
+use std::collections::HashMap;
 
 pub use self::error::{Result, Error};
 
 use imageutil::*;
+use animation::{Frame, is_gif, decode_gif, encode_gif, MAX_PIXELS};
+use fonts;
+use layout;
+use crop::smart_window;
+use fetch;
+use parse::Arguments;
+use emoji;
+use emoji::Piece;
+use markup;
+use markup::Run;
 
 use toml;
 
-use hyper::client::Client;
-use hyper::net::HttpsConnector;
-
 use url::Url;
 
-use textwrap::wrap;
-
-use image::{DynamicImage, GenericImage, RgbaImage};
+use image::imageops::resize;
+use image::{DynamicImage, GenericImage, GrayImage, ImageDecoder, ImageFormat, Pixel, RgbaImage};
 use image::Rgba;
 use image;
 
-use rusttype::{FontCollection, Font, Scale};
+use rusttype::{Font, Scale};
+use fonts::Family;
 
-use imageproc::drawing::{draw_text_mut, draw_hollow_rect_mut};
+use imageproc::drawing::{draw_text_mut, draw_hollow_rect_mut, draw_filled_rect_mut};
 use imageproc::rect;
 use imageproc::affine::rotate_with_default;
 use imageproc::affine::Interpolation;
 
-use hyper_native_tls::NativeTlsClient;
 
-const FONT: &[u8] = include_bytes!("Roboto.ttf");
 const DEG_2_RAD: f32 = PI / 180.0;
+///Smallest size text shrinks to when a feature doesn't set min_font_size.
+const DEFAULT_MIN_FONT_SIZE: f32 = 8.0;
+///The most pixels one render can hold at once, counted over the base image's frames, every layer
+///and every finished frame.
+const MAX_RENDER_PIXELS: u64 = 128 * 1024 * 1024;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Template {
@@ -41,6 +54,8 @@ pub struct Template {
     #[serde(default)]
     pub aliases: Vec<String>,
     pub short_name: String,
+    ///Font to use for every text feature, unless the feature picks its own.
+    pub font: Option<PathBuf>,
     pub features: Vec<Feature>,
 }
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
@@ -55,19 +70,99 @@ pub enum Alignment {
     Center,
     Right,
 }
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum VerticalAlignment {
+    Top,
+    Middle,
+    Bottom,
+}
+///How an image is fit into an image feature's rect.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Fit {
+    ///Scale the image to fit inside the rect, keeping its aspect ratio. Leaves bars on two sides.
+    Contain,
+    ///Scale the image to fill the rect, keeping its aspect ratio. Crops whatever overflows.
+    Cover,
+    ///Scale the image to exactly the rect, ignoring its aspect ratio.
+    Stretch,
+    ///Don't scale the image at all. Crops whatever overflows.
+    None,
+}
+///How image features choose which part of an image to keep when it's cropped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Crop {
+    ///Keep the part at the feature's anchor.
+    Anchor,
+    ///Keep the most interesting part of the image, found by looking for edges and skin tones.
+    Smart,
+}
+///Which part of the rect an image sticks to when it doesn't exactly fill it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+impl Anchor {
+    ///How far along the free space (horizontally, vertically) the image is placed, from 0 to 1.
+    fn factors(&self) -> (f32, f32) {
+        match *self {
+            Anchor::TopLeft => (0.0, 0.0),
+            Anchor::Top => (0.5, 0.0),
+            Anchor::TopRight => (1.0, 0.0),
+            Anchor::Left => (0.0, 0.5),
+            Anchor::Center => (0.5, 0.5),
+            Anchor::Right => (1.0, 0.5),
+            Anchor::BottomLeft => (0.0, 1.0),
+            Anchor::Bottom => (0.5, 1.0),
+            Anchor::BottomRight => (1.0, 1.0),
+        }
+    }
+}
+///The point a feature is rotated around.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum Pivot {
+    Named(NamedPivot),
+    ///An explicit point, in pixels of the template image.
+    Point([f32; 2]),
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum NamedPivot {
+    TopLeft,
+    Center,
+}
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Feature {
     pub kind: FeatureType,
+    ///A name users can give this feature's argument by, like `caption="some text"`.
+    pub id: Option<String>,
     pub x: u32,
     pub y: u32,
     pub w: u32,
     pub h: u32,
     pub rotation: Option<f32>,
+    pub pivot: Option<Pivot>,
+    ///Four points (top-left, top-right, bottom-right, bottom-left) to warp the feature onto.
+    pub corners: Option<[[f32; 2]; 4]>,
     pub font_size: Option<f32>,
+    pub min_font_size: Option<f32>,
+    pub max_font_size: Option<f32>,
     pub font_color: Option<[u8; 4]>,
     pub alignment: Option<Alignment>,
+    pub vertical_alignment: Option<VerticalAlignment>,
     pub stretch: Option<bool>,
+    pub fit: Option<Fit>,
+    pub anchor: Option<Anchor>,
+    pub crop: Option<Crop>,
     pub mask: Option<PathBuf>,
+    pub font: Option<PathBuf>,
     #[serde(default)]
     pub margin_left: u32,
     #[serde(default)]
@@ -76,6 +171,98 @@ pub struct Feature {
     pub margin_top: u32,
     #[serde(default)]
     pub margin_bottom: u32,
+    pub outline_color: Option<[u8; 4]>,
+    #[serde(default)]
+    pub outline_width: u32,
+    pub shadow_color: Option<[u8; 4]>,
+    pub shadow_offset: Option<[i32; 2]>,
+    ///The mask, decoded once when the template is loaded so it isn't read again for every frame.
+    #[serde(skip)]
+    pub mask_image: Option<Arc<GrayImage>>,
+}
+impl Feature {
+    ///Masks `layer` with this feature's mask, if it has one.
+    fn apply_mask(&self, layer: RgbaImage) -> Result<RgbaImage> {
+        match (&self.mask_image, &self.mask) {
+            (&Some(ref mask), _) => Ok(mask_image(layer, mask)),
+            (&None, &Some(ref path)) => Ok(mask_image(layer, &image::open(path)?.to_luma())),
+            (&None, &None) => Ok(layer),
+        }
+    }
+    ///The point this feature rotates around. Defaults to the top-left corner.
+    pub fn pivot_point(&self) -> (f32, f32) {
+        match self.pivot.unwrap_or(Pivot::Named(NamedPivot::TopLeft)) {
+            Pivot::Named(NamedPivot::TopLeft) => (self.x as f32, self.y as f32),
+            Pivot::Named(NamedPivot::Center) => (
+                self.x as f32 + self.w as f32 / 2.0,
+                self.y as f32 + self.h as f32 / 2.0,
+            ),
+            Pivot::Point(point) => (point[0], point[1]),
+        }
+    }
+    ///How image features fit images into their rect. Defaults to Contain, Stretch if the older
+    ///`stretch` flag is set, or Cover if smart cropping is on.
+    pub fn fit_mode(&self) -> Fit {
+        self.fit.unwrap_or(if self.stretch.unwrap_or(false) {
+            Fit::Stretch
+        } else if self.crop == Some(Crop::Smart) {
+            Fit::Cover
+        } else {
+            Fit::Contain
+        })
+    }
+    ///Works out how big an image of the given dimensions should be drawn, and where it goes
+    ///relative to the top-left of the feature. The offset is negative when the image overflows
+    ///the rect and gets cropped.
+    fn place_image(&self, image: &RgbaImage) -> ((u32, u32), (i32, i32)) {
+        let dimensions = image.dimensions();
+        let (width, height) = (dimensions.0 as f32, dimensions.1 as f32);
+        let (w, h) = (self.w as f32, self.h as f32);
+        let dim = match self.fit_mode() {
+            Fit::Stretch => (self.w, self.h),
+            Fit::None => dimensions,
+            fit => {
+                //contain scales until one side fits, cover until both sides are filled
+                let scale = if fit == Fit::Contain {
+                    (w / width).min(h / height)
+                } else {
+                    (w / width).max(h / height)
+                };
+                (
+                    ((width * scale).round() as u32).max(1),
+                    ((height * scale).round() as u32).max(1),
+                )
+            }
+        };
+        if self.crop == Some(Crop::Smart) && (dim.0 > self.w || dim.1 > self.h) {
+            let window = smart_window(image, dim, (self.w, self.h));
+            //centre any side that doesn't overflow, and crop the other to the chosen window
+            let offset = (
+                if dim.0 > self.w {
+                    -(window.0 as i32)
+                } else {
+                    ((w - dim.0 as f32) / 2.0).round() as i32
+                },
+                if dim.1 > self.h {
+                    -(window.1 as i32)
+                } else {
+                    ((h - dim.1 as f32) / 2.0).round() as i32
+                },
+            );
+            return (dim, offset);
+        }
+        let (anchor_x, anchor_y) = self.anchor.unwrap_or(Anchor::Center).factors();
+        let offset = (
+            ((w - dim.0 as f32) * anchor_x).round() as i32,
+            ((h - dim.1 as f32) * anchor_y).round() as i32,
+        );
+        (dim, offset)
+    }
+    ///The corners of the feature's rect, in the same order as `corners`.
+    fn rect_corners(&self) -> [(f32, f32); 4] {
+        let (x, y, w, h) = (self.x as f32, self.y as f32, self.w as f32, self.h as f32);
+        [(x, y), (x + w, y), (x + w, y + h), (x, y + h)]
+    }
 }
 impl Template {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Template> {
@@ -85,6 +272,13 @@ impl Template {
         file.read_to_string(&mut contents)?;
         let mut template: Template = toml::from_str(contents.as_str())?;
         template.image = path.parent().unwrap_or(path).join(&template.image);
+        if let Some(ref mut font_path) = template.font {
+            *font_path = path.parent().unwrap_or(path).join(&font_path);
+            //load it now so a bad font is caught along with the rest of the template
+            fonts::family(&font_path).map_err(|e| {
+                Error::Invalid(format!("Error loading font: {}", e.to_string()))
+            })?;
+        }
         let bg_image_dim;
         //make sure the image exists, is valid, and cache the dimensions in case there are any
         //masks.
@@ -99,17 +293,78 @@ impl Template {
                 ));
             }
         }
+        for (index, feature) in template.features.iter().enumerate() {
+            if let Some(ref id) = feature.id {
+                //ids have to be typeable before an = in the meme command
+                if id.is_empty() || !id.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    return Err(Error::Invalid(format!(
+                        "Feature id '{}' can only contain letters, digits and underscores",
+                        id
+                    )));
+                }
+                if template.features[..index].iter().any(|x| x.id.as_ref() == Some(id)) {
+                    return Err(Error::Invalid(
+                        format!("Feature id '{}' is used more than once", id),
+                    ));
+                }
+            }
+        }
         for feature in &mut template.features {
-            if feature.kind == FeatureType::Text || feature.kind == FeatureType::Either {
-                if let None = feature.font_size {
+            if let Some(corners) = feature.corners {
+                let rect = feature.rect_corners();
+                //anything else would warp into garbage
+                if !is_convex(&quad(&corners)) || homography(&quad(&corners), &rect).is_none() {
                     return Err(Error::Invalid(
-                        "Text feature is missing required field 'font_size'"
+                        "Feature corners must form a convex quadrilateral without crossing edges"
                             .to_owned(),
                     ));
                 }
+            }
+            if feature.kind == FeatureType::Text || feature.kind == FeatureType::Either {
+                if let None = feature.max_font_size {
+                    //font_size doubles as the maximum size
+                    feature.max_font_size = feature.font_size;
+                }
+                let max_font_size = match feature.max_font_size {
+                    Some(size) => size,
+                    None => {
+                        return Err(Error::Invalid(
+                            "Text feature is missing required field 'font_size'"
+                                .to_owned(),
+                        ));
+                    }
+                };
+                if let None = feature.min_font_size {
+                    feature.min_font_size = Some(DEFAULT_MIN_FONT_SIZE.min(max_font_size));
+                }
+                if feature.min_font_size.unwrap() <= 0.0 {
+                    return Err(Error::Invalid(
+                        "Minimum font size must be greater than zero".to_owned(),
+                    ));
+                }
+                if feature.min_font_size.unwrap() > max_font_size {
+                    return Err(Error::Invalid(
+                        "Minimum font size is larger than the maximum font size".to_owned(),
+                    ));
+                }
                 if let None = feature.font_color {
                     feature.font_color = Some([0, 0, 0, 255]); //default to black
                 }
+                if feature.outline_width > 0 && feature.outline_color.is_none() {
+                    feature.outline_color = Some([0, 0, 0, 255]); //default to black
+                }
+                if feature.shadow_offset.is_some() && feature.shadow_color.is_none() {
+                    feature.shadow_color = Some([0, 0, 0, 160]); //default to translucent black
+                }
+                if feature.shadow_color.is_some() && feature.shadow_offset.is_none() {
+                    feature.shadow_offset = Some([2, 2]);
+                }
+                if let Some(ref mut font_path) = feature.font {
+                    *font_path = path.parent().unwrap_or(path).join(&font_path);
+                    fonts::family(&font_path).map_err(|e| {
+                        Error::Invalid(format!("Error loading font: {}", e.to_string()))
+                    })?;
+                }
                 if feature.margin_left + feature.margin_right > feature.w {
                     return Err(Error::Invalid(
                         "Horizontal margins add up to more than feature's width"
@@ -138,6 +393,7 @@ impl Template {
                                         .to_string(),
                                 ));
                             }
+                            feature.mask_image = Some(Arc::new(img.to_luma()));
                         }
                         Err(e) => {
                             return Err(Error::Invalid(format!(
@@ -157,25 +413,15 @@ impl Template {
     fn generate_text_image(
         feature: &Feature,
         bg_image: &DynamicImage,
-        font: &Font,
+        fonts: &Family,
         show_rectangles: bool,
         text: &str,
     ) -> Result<RgbaImage> {
         assert!(feature.kind == FeatureType::Text || feature.kind == FeatureType::Either);
         let mut font_image = RgbaImage::new(bg_image.width(), bg_image.height());
         if show_rectangles {
-            //for debug and templates
-            draw_hollow_rect_mut(
-                &mut font_image,
-                rect::Rect::at(feature.x as i32, feature.y as i32).of_size(feature.w, feature.h),
-                Rgba([255, 0, 0, 255]),
-            );
+            draw_debug_rect(&mut font_image, feature);
         }
-        let mut height = feature.font_size.unwrap();
-        let mut scale = Scale {
-            x: height,
-            y: height,
-        };
         //rest of the calculations have to use the rect with margin factored in
         let feature_rect = Rect::new(
             feature.x + feature.margin_left, //offset left edge by margin_left
@@ -183,179 +429,464 @@ impl Template {
             feature.w - (feature.margin_left + feature.margin_right), //width = width - margins
             feature.h - (feature.margin_top + feature.margin_bottom), //height = height - margins
         );
-        let mut max_lines = (feature_rect.h as f32 / height).floor() as usize;
-        let mut char_width = (feature_rect.w as f32 * 2.4 / height).floor() as usize; //Magic Number (tm) to get char width from rect width
-        while wrap(text, char_width).len() > max_lines {
-            height -= 1.0;
-            scale = Scale {
-                x: height,
-                y: height,
-            };
-            max_lines = (feature_rect.h as f32 / height).floor() as usize;
-            char_width = (feature_rect.w as f32 * 2.4 / height).floor() as usize; //Magic Number (tm)
-        }
-        for (line_index, line) in
-            align_text(
-                text,
-                char_width,
-                feature.alignment.unwrap_or(Alignment::Left),
-            ).iter()
-                .enumerate()
-        {
-            if line_index >= max_lines {
-                break;
-            }
-            draw_text_mut(
+        //leave room for the outline on both sides so it doesn't spill out of the rect
+        let max_width = feature_rect.w as f32 - 2.0 * feature.outline_width as f32;
+        //markup like **bold** splits the text into styled runs
+        let runs = markup::parse(text);
+        //use the biggest size the text fits at
+        let (height, lines) = layout::fit_runs(
+            &runs,
+            max_width,
+            feature_rect.h as f32,
+            feature.min_font_size.unwrap(),
+            feature.max_font_size.unwrap(),
+            |scale, line| layout::runs_width(fonts, scale, line),
+        ).ok_or_else(|| Error::TextOverflow(markup::plain_text(&runs)))?;
+        let scale = Scale {
+            x: height,
+            y: height,
+        };
+        let alignment = feature.alignment.unwrap_or(Alignment::Left);
+        let top = layout::vertical_offset(
+            lines.len() as f32 * height,
+            feature_rect.h as f32,
+            feature.vertical_alignment.unwrap_or(VerticalAlignment::Top),
+        ) as u32;
+        for (line_index, line) in lines.iter().enumerate() {
+            let line_width = layout::runs_width(fonts, scale, line);
+            draw_line(
                 &mut font_image,
-                Rgba(feature.font_color.unwrap()),
-                feature_rect.x,
-                feature_rect.y + (line_index as f32 * height) as u32,
+                feature,
+                feature_rect.x + feature.outline_width +
+                    layout::align_offset(line_width, max_width, alignment) as u32,
+                feature_rect.y + top + (line_index as f32 * height) as u32,
                 scale,
-                &font,
+                fonts,
                 line,
             );
         }
-        if let Some(rotation) = feature.rotation {
-            font_image = rotate_with_default(
-                &font_image,
-                (feature.x as f32, feature.y as f32),
-                rotation * DEG_2_RAD,
-                Rgba([0, 0, 0, 0]),
-                Interpolation::Bilinear,
-            );
-        }
+        font_image = transform_feature(font_image, feature);
         //masking: mask the font_image with the mask bitmap (if given)
-        if let Some(ref path) = feature.mask {
-            let mask = image::open(path)?;
-            font_image = mask_image(font_image, &mask.to_luma());
+        feature.apply_mask(font_image)
+    }
+    ///The fonts a text feature should be drawn with: its own if it has one, otherwise the
+    ///template's.
+    fn feature_fonts(feature: &Feature, template_fonts: &Family) -> Result<Family> {
+        match feature.font {
+            Some(ref path) => fonts::family(path),
+            None => Ok(template_fonts.clone()),
         }
-        Ok(font_image)
     }
-    fn generate_image_image(
-        feature: &Feature,
-        bg_image: &DynamicImage,
-        url: &str,
-    ) -> Result<RgbaImage> {
-        let mut image = Vec::new();
-        if let Ok(url) = Url::parse(url) {
-            let ssl = NativeTlsClient::new().unwrap();
-            let connector = HttpsConnector::new(ssl);
-            let client = Client::with_connector(connector);
-            let resp = client.get(url.clone()).send();
-            match resp {
-                Ok(mut resp) => {
-                    resp.read_to_end(&mut image)?;
+    ///Lines `arguments` up with the features: keyed arguments go to the feature with that id, and
+    ///positional ones fill the other features in order. A keyed argument whose id isn't a feature's
+    ///(like `E=mc2`) is positional after all, as the whole word. Features left without an argument
+    ///get an empty one, which leaves them blank.
+    pub fn arrange(&self, arguments: &Arguments) -> Result<Vec<String>> {
+        let mut texts: Vec<Option<String>> = vec![None; self.features.len()];
+        //positional arguments, with the keyed ones that turned out not to be keys back in place
+        let mut positional = Vec::new();
+        let mut unknown = Vec::new();
+        for &(ref key, ref value, position) in &arguments.keyed {
+            match self.features.iter().position(|x| x.id.as_ref() == Some(key)) {
+                Some(index) => {
+                    if texts[index].is_some() {
+                        return Err(Error::Invalid(format!("{} was given more than once", key)));
+                    }
+                    texts[index] = Some(value.clone());
+                }
+                None => unknown.push((position, format!("{}={}", key, value))),
+            }
+        }
+        let mut unknown = unknown.into_iter().peekable();
+        for (index, text) in arguments.positional.iter().enumerate() {
+            while unknown.peek().map_or(false, |x| x.0 == index) {
+                positional.push(unknown.next().unwrap().1);
+            }
+            positional.push(text.clone());
+        }
+        positional.extend(unknown.map(|x| x.1));
+        let mut positional = positional.into_iter();
+        for text in texts.iter_mut().filter(|x| x.is_none()) {
+            *text = positional.next();
+        }
+        let mut texts = texts
+            .into_iter()
+            .map(|x| x.unwrap_or_default())
+            .collect::<Vec<String>>();
+        while texts.last().map_or(false, |x| x.is_empty()) {
+            texts.pop();
+        }
+        Ok(texts)
+    }
+    ///Downloads the images for every image feature at once, keyed by feature index. A single
+    ///emoji gets its picture from the emoji set (and is an error for an image feature if the set
+    ///doesn't have it), and other inputs to image features that aren't URLs get the placeholder
+    ///image.
+    fn fetch_images(&self, text: &[&str]) -> Result<HashMap<usize, Arc<Vec<u8>>>> {
+        let mut images = HashMap::new();
+        let mut indices = Vec::new();
+        let mut urls = Vec::new();
+        let schemes = fetch::allowed_schemes();
+        for (index, (feature, input)) in self.features.iter().zip(text.iter()).enumerate() {
+            if input.is_empty() {
+                continue; //left blank
+            }
+            if feature.kind != FeatureType::Text && emoji::is_emoji(input) {
+                images.insert(index, Arc::new(emoji::data(input)?));
+                continue;
+            }
+            //Either features can draw it as text instead, but an image feature has nothing to show
+            if feature.kind == FeatureType::Image && emoji::looks_like_emoji(input) {
+                return Err(Error::Invalid(
+                    format!("There's no picture for the emoji {}", input.trim()),
+                ));
+            }
+            let url = match feature.kind {
+                FeatureType::Image => Url::parse(input).ok(),
+                FeatureType::Either => either_link(input, &schemes),
+                FeatureType::Text => continue,
+            };
+            match (feature.kind, url) {
+                (_, Some(url)) => {
+                    indices.push(index);
+                    urls.push(url);
                 }
-                Err(e) => {
-                    println!("error in url {}: {}", url, e);
-                    let mut placeholder = File::open("./placeholder.png")?;
-                    placeholder.read_to_end(&mut image)?;
+                (FeatureType::Image, None) => {
+                    let mut image = Vec::new();
+                    File::open("./placeholder.png")?.read_to_end(&mut image)?;
+                    images.insert(index, Arc::new(image));
                 }
+                _ => {}
             }
-        } else {
-            let mut placeholder = File::open("./placeholder.png")?;
-            placeholder.read_to_end(&mut image)?;
         }
-        let mut underlay_image = RgbaImage::new(bg_image.width(), bg_image.height());
-
-        let overlay_image = image::load_from_memory(image.as_slice())?;
-        let bg_aspect = feature.w as f32 / feature.h as f32;
-        let mut dim = overlay_image.dimensions();
-        let mut offset = (0u32, 0u32);
-        if !feature.stretch.unwrap_or(false) {
-            let aspect = dim.0 as f32 / dim.1 as f32; //width over height
-            if aspect > bg_aspect {
-                //width > height
-                dim.0 = feature.w;
-                dim.1 = (1.0 / aspect * feature.w as f32) as u32;
-                offset.1 = ((feature.h - dim.1) as f32 / 2.0) as u32;
-            } else {
-                //height > width or height = width
-                dim.1 = feature.h;
-                dim.0 = (aspect * feature.h as f32) as u32;
-                offset.0 = ((feature.w - dim.0) as f32 / 2.0) as u32;
+        for (index, result) in indices.into_iter().zip(fetch::fetch_all(urls.as_slice())) {
+            images.insert(index, result?);
+        }
+        Ok(images)
+    }
+    ///Decodes image data into frames. Animated GIFs yield every frame (up to MAX_FRAMES), anything
+    ///else yields a single frame. Images bigger than MAX_PIXELS are refused before they're decoded.
+    fn decode_frames(data: &[u8]) -> Result<Vec<Frame>> {
+        if is_gif(data) {
+            let frames = decode_gif(data)?;
+            if frames.is_empty() {
+                return Err(Error::Invalid("GIF doesn't contain any frames".to_owned()));
             }
+            Ok(frames)
         } else {
-            dim = (feature.w, feature.h);
+            //refuse huge images before decoding them, from the size their header claims
+            let (width, height) = image_dimensions(data)?;
+            if width as u64 * height as u64 > MAX_PIXELS {
+                return Err(Error::Invalid(format!("Image is too big ({}x{})", width, height)));
+            }
+            let image = image::load_from_memory(data)?;
+            Ok(vec![
+                Frame {
+                    image: image.to_rgba(),
+                    delay: 0,
+                },
+            ])
         }
-        paste_image_resized(
-            &overlay_image,
+    }
+    ///Generates one image layer per frame of the image in `data`, counting them against `budget`.
+    fn generate_image_frames(
+        feature: &Feature,
+        bg_image: &DynamicImage,
+        show_rectangles: bool,
+        data: &[u8],
+        budget: &mut u64,
+    ) -> Result<Vec<Frame>> {
+        let overlay_frames = Template::decode_frames(data)?;
+        let layer_pixels = bg_image.width() as u64 * bg_image.height() as u64;
+        spend(budget, overlay_frames.len() as u64 * layer_pixels)?;
+        //place every frame the same way as the first, so animations don't jump around
+        let placement = feature.place_image(&overlay_frames[0].image);
+        overlay_frames
+            .into_iter()
+            .map(|frame| -> Result<Frame> {
+                Ok(Frame {
+                    image: Template::generate_image_image(
+                        feature,
+                        bg_image,
+                        show_rectangles,
+                        &frame.image,
+                        placement,
+                    )?,
+                    delay: frame.delay,
+                })
+            })
+            .collect()
+    }
+    fn generate_image_image(
+        feature: &Feature,
+        bg_image: &DynamicImage,
+        show_rectangles: bool,
+        overlay_image: &RgbaImage,
+        placement: ((u32, u32), (i32, i32)),
+    ) -> Result<RgbaImage> {
+        let mut underlay_image = RgbaImage::new(bg_image.width(), bg_image.height());
+
+        let (dim, offset) = placement;
+        paste_image_resized_clipped(
+            overlay_image,
             &mut underlay_image,
-            feature.x + offset.0,
-            feature.y + offset.1,
+            feature.x as i32 + offset.0,
+            feature.y as i32 + offset.1,
             dim.0,
             dim.1,
+            (feature.x, feature.y, feature.w, feature.h),
         );
-
-        if let Some(rotation) = feature.rotation {
-            underlay_image = rotate_with_default(
-                &underlay_image,
-                (feature.x as f32, feature.y as f32),
-                rotation * DEG_2_RAD,
-                Rgba([0, 0, 0, 0]),
-                Interpolation::Bilinear,
-            );
+        if show_rectangles {
+            draw_debug_rect(&mut underlay_image, feature);
         }
+
+        underlay_image = transform_feature(underlay_image, feature);
         //masking: mask the underlay_image with the mask bitmap (if given)
-        if let Some(ref path) = feature.mask {
-            let mask = image::open(path)?;
-            underlay_image = mask_image(underlay_image, &mask.to_luma());
+        feature.apply_mask(underlay_image)
+    }
+    ///The feature with the render options' overrides applied.
+    fn styled_feature(feature: &Feature, options: &RenderOptions) -> Feature {
+        let mut feature = feature.clone();
+        if let Some(color) = options.color {
+            feature.font_color = Some(color);
         }
-
-        Ok(underlay_image)
+        if options.font.is_some() {
+            feature.font = None; //the picked font replaces every other one
+        }
+        feature
     }
-    pub fn render(&self, text: &[&str], show_rectangles: bool) -> Result<DynamicImage> {
+    ///Renders the template with the given texts/urls, one per feature. If the base image or any of
+    ///the images are animated GIFs, every frame is rendered and an animation is returned.
+    pub fn render(&self, text: &[&str], options: &RenderOptions) -> Result<Rendered> {
+        let show_rectangles = options.show_rectangles;
         //load image
-        let mut bg_image = image::open(&self.image)?;
-        if self.features.len() == 0 {
-            return Ok(bg_image); //no need to render any more
+        let mut data = Vec::new();
+        File::open(&self.image)?.read_to_end(&mut data)?;
+        let base_frames = Template::decode_frames(data.as_slice())?;
+        let bg_image = DynamicImage::ImageRgba8(base_frames[0].image.clone());
+        if self.features.len() == 0 && base_frames.len() == 1 && !options.deepfry &&
+            options.scale.is_none()
+        {
+            return Ok(Rendered::Still(bg_image)); //no need to render any more
         }
-        let font = Vec::from(FONT);
-        let font = FontCollection::from_bytes(font).into_font().unwrap();
+        let canvas_pixels = bg_image.width() as u64 * bg_image.height() as u64;
+        let mut budget = 0;
+        spend(&mut budget, base_frames.len() as u64 * canvas_pixels)?;
+        let template_fonts = match (&options.font, &self.font) {
+            (&Some(ref name), _) => fonts::named(name)?,
+            (&None, &Some(ref path)) => fonts::family(path)?,
+            (&None, &None) => fonts::default_family()?,
+        };
+        //download every image up front, all at once
+        let images = self.fetch_images(text)?;
+        //every feature becomes a list of layers: one per frame, or just one if it's still
+        let mut layers: Vec<Vec<Frame>> = Vec::new();
         for (index, feature) in self.features.iter().enumerate() {
-            if index >= text.len() {
-                break; //no text provided, leave blank
+            let feature = &Template::styled_feature(feature, options);
+            if index >= text.len() || text[index].is_empty() {
+                continue; //no text provided, leave blank
             } else {
                 match feature.kind {
                     FeatureType::Text => {
+                        spend(&mut budget, canvas_pixels)?;
+                        let fonts = Template::feature_fonts(feature, &template_fonts)?;
                         let font_image = Template::generate_text_image(
                             feature,
                             &bg_image,
-                            &font,
+                            &fonts,
                             show_rectangles,
                             text[index],
                         )?;
-                        paste_image(&font_image, &mut bg_image, 0, 0);
+                        layers.push(vec![
+                            Frame {
+                                image: font_image,
+                                delay: 0,
+                            },
+                        ]);
                     }
                     FeatureType::Image => {
-                        let underlay_image =
-                            Template::generate_image_image(feature, &bg_image, text[index])?;
-                        paste_image(&underlay_image, &mut bg_image, 0, 0);
+                        layers.push(Template::generate_image_frames(
+                            feature,
+                            &bg_image,
+                            show_rectangles,
+                            images[&index].as_slice(),
+                            &mut budget,
+                        )?);
                     }
                     FeatureType::Either => {
                         //decide whether it is an image or a text
-                        let image;
-                        if let Ok(_) = Url::parse(text[index]) {
+                        if let Some(image) = images.get(&index) {
                             //it's an image!
-                            image =
-                                Template::generate_image_image(feature, &bg_image, text[index])?;
+                            layers.push(Template::generate_image_frames(
+                                feature,
+                                &bg_image,
+                                show_rectangles,
+                                image.as_slice(),
+                                &mut budget,
+                            )?);
                         } else {
                             //it's text.
-                            image = Template::generate_text_image(
+                            spend(&mut budget, canvas_pixels)?;
+                            let fonts = Template::feature_fonts(feature, &template_fonts)?;
+                            let font_image = Template::generate_text_image(
                                 feature,
                                 &bg_image,
-                                &font,
+                                &fonts,
                                 show_rectangles,
                                 text[index],
                             )?;
+                            layers.push(vec![
+                                Frame {
+                                    image: font_image,
+                                    delay: 0,
+                                },
+                            ]);
                         }
-                        paste_image(&image, &mut bg_image, 0, 0);
                     }
                 }
             }
         }
-        Ok(bg_image)
+        //whichever input has the most frames sets the timing, and shorter animations loop
+        let driver = iter::once(&base_frames)
+            .chain(layers.iter())
+            .max_by_key(|frames| frames.len())
+            .unwrap();
+        //the finished frames are kept at the scaled size, so that's what counts
+        let (width, height) = bg_image.dimensions();
+        let (width, height) = match options.scale {
+            Some(scale) => (
+                ((width as f32 * scale).round() as u32).max(1),
+                ((height as f32 * scale).round() as u32).max(1),
+            ),
+            None => (width, height),
+        };
+        spend(&mut budget, driver.len() as u64 * width as u64 * height as u64)?;
+        let mut frames = Vec::new();
+        for (frame_index, driver_frame) in driver.iter().enumerate() {
+            let mut canvas = base_frames[frame_index % base_frames.len()].image.clone();
+            for layer in &layers {
+                paste_image(&layer[frame_index % layer.len()].image, &mut canvas, 0, 0);
+            }
+            if options.deepfry {
+                canvas = deep_fry(&canvas)?;
+            }
+            if options.scale.is_some() {
+                canvas = resize(&canvas, width, height, image::FilterType::Triangle);
+            }
+            frames.push(Frame {
+                image: canvas,
+                delay: driver_frame.delay,
+            });
+        }
+        if frames.len() == 1 {
+            Ok(Rendered::Still(
+                DynamicImage::ImageRgba8(frames.pop().unwrap().image),
+            ))
+        } else {
+            Ok(Rendered::Animated(frames))
+        }
+    }
+}
+///Tweaks to a single render, on top of what the template says.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RenderOptions {
+    ///Draw the outlines of every feature, for previewing templates.
+    pub show_rectangles: bool,
+    ///The format to send the meme in, instead of picking PNG or GIF by whether it's animated.
+    pub format: Option<OutputFormat>,
+    ///How much to resize the finished meme by.
+    pub scale: Option<f32>,
+    ///The name of a font from the fonts folder to draw all text with (see `fonts::named`).
+    pub font: Option<String>,
+    ///The color to draw all text in.
+    pub color: Option<[u8; 4]>,
+    ///Crank up the contrast and saturation and add JPEG artifacts.
+    pub deepfry: bool,
+}
+///A format a meme can be encoded in.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Gif,
+}
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Gif => "gif",
+        }
+    }
+}
+///The output of rendering a template.
+pub enum Rendered {
+    Still(DynamicImage),
+    Animated(Vec<Frame>),
+}
+impl Rendered {
+    ///Encodes the meme for uploading: animations as GIF and still images as PNG. Returns the
+    ///encoded bytes along with the matching file extension.
+    pub fn encode(&self) -> Result<(Vec<u8>, &'static str)> {
+        match *self {
+            Rendered::Still(_) => self.encode_as(OutputFormat::Png),
+            Rendered::Animated(_) => self.encode_as(OutputFormat::Gif),
+        }
+    }
+    ///Encodes the meme in `format`, returning the encoded bytes along with the matching file
+    ///extension. Only GIFs keep the animation; other formats get the first frame.
+    pub fn encode_as(&self, format: OutputFormat) -> Result<(Vec<u8>, &'static str)> {
+        let mut buf = Vec::new();
+        match (format, self) {
+            (OutputFormat::Gif, &Rendered::Animated(ref frames)) => buf = encode_gif(frames)?,
+            (OutputFormat::Gif, &Rendered::Still(ref still)) => {
+                buf = encode_gif(&[
+                    Frame {
+                        image: still.to_rgba(),
+                        delay: 0,
+                    },
+                ])?
+            }
+            (OutputFormat::Png, _) => self.first_frame().save(&mut buf, image::ImageFormat::PNG)?,
+            (OutputFormat::Jpeg, _) => {
+                self.first_frame().save(&mut buf, image::ImageFormat::JPEG)?
+            }
+        }
+        Ok((buf, format.extension()))
+    }
+    ///The first frame of the meme, or the whole meme if it's still.
+    pub fn first_frame(&self) -> DynamicImage {
+        match *self {
+            Rendered::Still(ref still) => still.clone(),
+            Rendered::Animated(ref frames) => DynamicImage::ImageRgba8(frames[0].image.clone()),
+        }
+    }
+}
+///Finds a template by its short name or one of its aliases.
+pub fn get_template<'a>(templates: &'a [Template], name: &str) -> Option<&'a Template> {
+    templates.iter().find(|template| {
+        template.short_name == name || template.aliases.contains(&name.to_owned())
+    })
+}
+///Loads every `.toml` template in `dir`. On failure, returns the offending file (if any) along
+///with the error.
+pub fn load_templates<P: AsRef<Path>>(
+    dir: P,
+) -> ::std::result::Result<Vec<Template>, (Option<String>, Error)> {
+    let mut templates = Vec::new();
+    let files = fs::read_dir(dir).map_err(|e| (None, Error::Io(e)))?;
+    for file in files {
+        let path = file.map_err(|e| (None, Error::Io(e)))?.path();
+        match path.extension().map(|e| e.to_str().unwrap_or("")) {
+            Some("toml") => {
+                templates.push(Template::from_file(path.as_path()).map_err(|e| {
+                    (Some(path.to_str().unwrap().to_owned()), e)
+                })?);
+            }
+            _ => {}
+        }
     }
+    Ok(templates)
 }
 struct Rect {
     x: u32,
@@ -373,25 +904,282 @@ impl Rect {
         }
     }
 }
-const PAD_CHAR: char = ' '; //this is actually an en space, which is significantly wider when rendered
-fn align_text(text: &str, char_width: usize, alignment: Alignment) -> Vec<String> {
-    let text = wrap(text, char_width);
-    let mut padded = Vec::new();
-    for line in text.into_iter() {
-        let mut padding = 0;
-        match alignment {
-            Alignment::Center => {
-                padding = ((char_width - line.chars().count()) as f32 / 2.0).floor() as usize;
+///Outlines the feature's rect, for debugging and showing off templates.
+fn draw_debug_rect(image: &mut RgbaImage, feature: &Feature) {
+    draw_hollow_rect_mut(
+        image,
+        rect::Rect::at(feature.x as i32, feature.y as i32).of_size(feature.w, feature.h),
+        Rgba([255, 0, 0, 255]),
+    );
+}
+///Reads the size of the still image in `data` from its header, without decoding the rest.
+fn image_dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    let reader = Cursor::new(data);
+    let dimensions = match image::guess_format(data)? {
+        ImageFormat::PNG => image::png::PNGDecoder::new(reader).dimensions(),
+        ImageFormat::JPEG => image::jpeg::JPEGDecoder::new(reader).dimensions(),
+        ImageFormat::WEBP => image::webp::WebpDecoder::new(reader).dimensions(),
+        ImageFormat::BMP => image::bmp::BMPDecoder::new(reader).dimensions(),
+        ImageFormat::TIFF => image::tiff::TIFFDecoder::new(reader)?.dimensions(),
+        ImageFormat::ICO => image::ico::ICODecoder::new(reader)?.dimensions(),
+        ImageFormat::HDR => image::hdr::HDRAdapter::new(reader)?.dimensions(),
+        ImageFormat::PPM => image::ppm::PPMDecoder::new(reader)?.dimensions(),
+        //GIFs go through decode_gif, and TGA is never guessed
+        format => {
+            return Err(Error::Invalid(format!("Can't read {:?} images", format)));
+        }
+    };
+    Ok(dimensions?)
+}
+///Counts `pixels` more against a render's budget, failing once it's over MAX_RENDER_PIXELS.
+fn spend(budget: &mut u64, pixels: u64) -> Result<()> {
+    *budget += pixels;
+    if *budget > MAX_RENDER_PIXELS {
+        Err(Error::Invalid(
+            "This meme is too big to render. Try smaller or shorter GIFs".to_owned(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+///The link an Either feature's input points to, if it's a link at all. Captions like `POV: you`
+///parse as URLs too, so only links with a scheme images can be downloaded from count.
+fn either_link(input: &str, schemes: &[String]) -> Option<Url> {
+    let url = match Url::parse(input.trim()) {
+        Ok(url) => url,
+        Err(_) => return None,
+    };
+    if schemes.iter().any(|x| x == url.scheme()) {
+        Some(url)
+    } else {
+        None
+    }
+}
+///Moves a feature's layer into place: warped onto its corners if it has them, otherwise rotated
+///by its rotation (if any) around its pivot.
+fn transform_feature(image: RgbaImage, feature: &Feature) -> RgbaImage {
+    if let Some(corners) = feature.corners {
+        //corners were validated when the template was loaded
+        warp_perspective(
+            &image,
+            feature.x,
+            feature.y,
+            feature.w,
+            feature.h,
+            &quad(&corners),
+        ).unwrap()
+    } else if let Some(rotation) = feature.rotation {
+        rotate_with_default(
+            &image,
+            feature.pivot_point(),
+            rotation * DEG_2_RAD,
+            Rgba([0, 0, 0, 0]),
+            Interpolation::Bilinear,
+        )
+    } else {
+        image
+    }
+}
+fn quad(corners: &[[f32; 2]; 4]) -> [(f32, f32); 4] {
+    [
+        (corners[0][0], corners[0][1]),
+        (corners[1][0], corners[1][1]),
+        (corners[2][0], corners[2][1]),
+        (corners[3][0], corners[3][1]),
+    ]
+}
+///Draws one line of styled runs. Emoji are drawn inline as pictures from the emoji set, scaled to
+///the font size, and styles the fonts have no variant for are faked.
+fn draw_line(
+    image: &mut RgbaImage,
+    feature: &Feature,
+    x: u32,
+    y: u32,
+    scale: Scale,
+    fonts: &Family,
+    line: &[Run],
+) {
+    let mut cursor = x as f32;
+    for run in line {
+        let face = fonts.select(run.style.bold, run.style.italic);
+        let style = TextStyle {
+            font: &**face.font,
+            scale: scale,
+            color: run.style.color.unwrap_or(feature.font_color.unwrap()),
+            bold: if face.fake_bold {
+                layout::bold_offset(scale)
+            } else {
+                0
+            },
+        };
+        let start = cursor;
+        for piece in emoji::split(run.text.as_str()) {
+            let text = match piece {
+                Piece::Text(text) => text,
+                Piece::Emoji(text) => {
+                    let size = layout::emoji_size(scale);
+                    match emoji::image(text) {
+                        Ok(picture) => {
+                            let side = (size.round() as u32).max(1);
+                            let picture =
+                                resize(&*picture, side, side, image::FilterType::Triangle);
+                            paste_image(&picture, image, cursor as u32, y);
+                            cursor += size;
+                            continue;
+                        }
+                        //the picture couldn't be loaded, so fall back to the font
+                        Err(_) => text,
+                    }
+                }
+            };
+            if face.fake_italic {
+                draw_slanted_text(image, feature, cursor as u32, y, text, &style);
+            } else {
+                draw_styled_text(image, feature, cursor as u32, y, text, &style);
             }
-            Alignment::Right => {
-                padding = char_width - line.chars().count();
+            cursor += layout::text_width(style.font, scale, text);
+        }
+        cursor += style.bold as f32;
+        if run.style.strike {
+            let width = (cursor - start).round() as u32;
+            draw_strike(image, feature, start as u32, y, width, &style);
+        }
+    }
+}
+///How a run of text is drawn.
+struct TextStyle<'a> {
+    font: &'a Font<'a>,
+    scale: Scale,
+    color: [u8; 4],
+    ///How many pixels the text is smeared to the right to fake bold, or 0.
+    bold: u32,
+}
+///The offsets the text is stamped at to make an outline `width` pixels thick.
+fn outline_stamps(width: i32) -> Vec<(i32, i32)> {
+    let mut stamps = Vec::new();
+    for dx in -width..width + 1 {
+        for dy in -width..width + 1 {
+            if dx * dx + dy * dy <= width * width {
+                stamps.push((dx, dy));
             }
-            _ => {}
         }
-        let padded_line = iter::repeat(PAD_CHAR).take(padding).collect::<String>() + line.as_str();
-        padded.push(padded_line);
     }
-    padded
+    stamps
+}
+///Draws a piece of text, along with its drop shadow and outline if the feature has them.
+fn draw_styled_text(
+    image: &mut RgbaImage,
+    feature: &Feature,
+    x: u32,
+    y: u32,
+    text: &str,
+    style: &TextStyle,
+) {
+    //the outline is made by stamping the text at every offset within outline_width
+    let width = feature.outline_width as i32;
+    let stamps = outline_stamps(width);
+    let offset = |position: u32, delta: i32| (position as i32 + delta).max(0) as u32;
+    //faked bold is the text drawn again, a pixel further right each time
+    let draw = |image: &mut RgbaImage, color: [u8; 4], dx: i32, dy: i32| {
+        for bold in 0..style.bold as i32 + 1 {
+            draw_text_mut(
+                image,
+                Rgba(color),
+                offset(x, dx + bold),
+                offset(y, dy),
+                style.scale,
+                style.font,
+                text,
+            );
+        }
+    };
+    if let (Some(color), Some(shadow_offset)) = (feature.shadow_color, feature.shadow_offset) {
+        //the shadow is cast by the outlined text, so it gets stamped too
+        for &(dx, dy) in &stamps {
+            draw(image, color, shadow_offset[0] + dx, shadow_offset[1] + dy);
+        }
+    }
+    if let Some(color) = feature.outline_color {
+        if width > 0 {
+            for &(dx, dy) in &stamps {
+                draw(image, color, dx, dy);
+            }
+        }
+    }
+    draw(image, style.color, 0, 0);
+}
+///How far italic text is slanted when faking it: pixels right per pixel above the baseline.
+const ITALIC_SLANT: f32 = 0.2;
+///Draws text slanted like italics, for fonts without an italic variant: it's drawn upright on a
+///layer just big enough for it, then every row is shifted right by how far above the baseline it
+///is.
+fn draw_slanted_text(
+    image: &mut RgbaImage,
+    feature: &Feature,
+    x: u32,
+    y: u32,
+    text: &str,
+    style: &TextStyle,
+) {
+    //room for the outline and shadow around the text, and glyphs that overhang a little
+    let shadow = feature
+        .shadow_offset
+        .map_or(0, |offset| offset[0].abs().max(offset[1].abs()) as u32);
+    let margin = feature.outline_width + shadow + 2;
+    let v_metrics = style.font.v_metrics(style.scale);
+    let text_width = layout::text_width(style.font, style.scale, text).ceil() as u32 + style.bold;
+    let text_height = (v_metrics.ascent - v_metrics.descent).ceil() as u32;
+    //where the layer goes on the image
+    let (left, top) = (x.saturating_sub(margin), y.saturating_sub(margin));
+    if left >= image.width() || top >= image.height() {
+        return;
+    }
+    let width = (x - left + text_width + margin).min(image.width() - left);
+    let height = (y - top + text_height + margin).min(image.height() - top);
+    let mut upright = RgbaImage::new(width, height);
+    draw_styled_text(&mut upright, feature, x - left, y - top, text, style);
+    let baseline = y as f32 + v_metrics.ascent;
+    for k in 0..upright.height() {
+        let row = top + k;
+        let shift = ((baseline - row as f32) * ITALIC_SLANT).round() as i32;
+        for i in 0..upright.width() {
+            let pixel = *upright.get_pixel(i, k);
+            let dx = (left + i) as i32 + shift;
+            if pixel.data[3] == 0 || dx < 0 || dx >= image.width() as i32 {
+                continue;
+            }
+            let mut other_pixel = *image.get_pixel(dx as u32, row);
+            other_pixel.blend(&pixel);
+            image.put_pixel(dx as u32, row, other_pixel);
+        }
+    }
+}
+///Draws a strikethrough line `width` pixels long across text drawn at (`x`, `y`), outlined like
+///the text is.
+fn draw_strike(
+    image: &mut RgbaImage,
+    feature: &Feature,
+    x: u32,
+    y: u32,
+    width: u32,
+    style: &TextStyle,
+) {
+    if width == 0 {
+        return;
+    }
+    let thickness = (style.scale.y / 16.0).round().max(1.0) as u32;
+    //through the middle of the lowercase letters
+    let middle = y as f32 + style.font.v_metrics(style.scale).ascent - style.scale.y * 0.3;
+    let top = (middle - thickness as f32 / 2.0).round() as i32;
+    let line = |dx: i32, dy: i32| {
+        rect::Rect::at(x as i32 + dx, top + dy).of_size(width, thickness)
+    };
+    if let Some(outline) = feature.outline_color {
+        for &(dx, dy) in &outline_stamps(feature.outline_width as i32) {
+            draw_filled_rect_mut(image, line(dx, dy), Rgba(outline));
+        }
+    }
+    draw_filled_rect_mut(image, line(0, 0), Rgba(style.color));
 }
 mod error {
     use std::result;
@@ -403,6 +1191,10 @@ mod error {
 
     use image;
 
+    use gif;
+
+    use fetch::FetchError;
+
     pub type Result<T> = result::Result<T, self::Error>;
 
     #[derive(Debug)]
@@ -411,6 +1203,9 @@ mod error {
         Deserialize(toml::de::Error),
         Invalid(String),
         Image(image::ImageError),
+        Gif(gif::DecodingError),
+        TextOverflow(String),
+        Fetch(FetchError),
     }
 
     impl fmt::Display for Error {
@@ -420,6 +1215,11 @@ mod error {
                 Error::Deserialize(ref e) => e.to_string(),
                 Error::Invalid(ref message) => message.clone(),
                 Error::Image(ref e) => e.to_string(),
+                Error::Gif(ref e) => e.to_string(),
+                Error::TextOverflow(ref text) => {
+                    format!("\"{}\" is too long to fit, even at the smallest font size", text)
+                }
+                Error::Fetch(ref e) => e.to_string(),
             };
             write!(f, "{}", message)
         }
@@ -433,6 +1233,9 @@ mod error {
                     "The template was successfully deserialized, but contained invalid data."
                 }
                 Error::Image(ref e) => e.description(),
+                Error::Gif(ref e) => e.description(),
+                Error::TextOverflow(_) => "The text doesn't fit, even at the minimum font size.",
+                Error::Fetch(ref e) => e.description(),
             }
         }
     }
@@ -451,4 +1254,236 @@ mod error {
             Error::Image(e)
         }
     }
+    impl From<gif::DecodingError> for Error {
+        fn from(e: gif::DecodingError) -> Error {
+            Error::Gif(e)
+        }
+    }
+    impl From<FetchError> for Error {
+        fn from(e: FetchError) -> Error {
+            Error::Fetch(e)
+        }
+    }
+}
+#[cfg(test)]
+mod test {
+    use toml;
+
+    use image::{Rgba, RgbaImage};
+
+    use rusttype::Scale;
+
+    use fonts;
+    use parse::parse_arguments;
+    use template::{draw_slanted_text, draw_styled_text, either_link, image_dimensions, spend,
+                   transform_feature, Error, Feature, Template, TextStyle, MAX_RENDER_PIXELS};
+
+    ///A feature read from the TOML for it, as in a template file.
+    fn feature(toml: &str) -> Feature {
+        toml::from_str(toml).unwrap()
+    }
+    ///Every pixel of `image` that's exactly `color`.
+    fn pixels_of(image: &RgbaImage, color: [u8; 4]) -> Vec<(u32, u32)> {
+        image
+            .enumerate_pixels()
+            .filter(|&(_, _, pixel)| *pixel == Rgba(color))
+            .map(|(x, y, _)| (x, y))
+            .collect()
+    }
+
+    #[test]
+    fn arranges_keyed_arguments() {
+        let template: Template = toml::from_str(
+            r#"
+            name="Test"
+            short_name="test"
+            image="./test.png"
+            [[features]]
+            id="left"
+            kind="Image"
+            x=0
+            y=0
+            w=10
+            h=10
+            [[features]]
+            kind="Text"
+            x=0
+            y=10
+            w=10
+            h=10
+            [[features]]
+            id="caption"
+            kind="Text"
+            x=10
+            y=10
+            w=10
+            h=10
+            "#,
+        ).unwrap();
+        let arrange = |input: &[&str]| template.arrange(&parse_arguments(input).unwrap());
+        assert_eq!(
+            arrange(&["caption=\"ten spiders\"", "a"]).unwrap(),
+            vec!["a".to_owned(), String::new(), "ten spiders".to_owned()]
+        );
+        assert_eq!(
+            arrange(&["b", "left=a"]).unwrap(),
+            vec!["a".to_owned(), "b".to_owned()]
+        );
+        //words that only look like keys stay where they were, whole
+        assert_eq!(
+            arrange(&["x=5", "b", "E=mc2"]).unwrap(),
+            vec!["x=5".to_owned(), "b".to_owned(), "E=mc2".to_owned()]
+        );
+        assert_eq!(
+            arrange(&["left=a", "x=5", "b"]).unwrap(),
+            vec!["a".to_owned(), "x=5".to_owned(), "b".to_owned()]
+        );
+        assert!(arrange(&["left=a", "left=b"]).is_err());
+    }
+    #[test]
+    fn outlines_and_shadows_text() {
+        let (white, red, blue) = ([255, 255, 255, 255], [255, 0, 0, 255], [0, 0, 255, 255]);
+        let font = fonts::default_font();
+        let style = TextStyle {
+            font: &*font,
+            scale: Scale { x: 40.0, y: 40.0 },
+            color: white,
+            bold: 0,
+        };
+        let draw = |feature: &Feature| {
+            let mut image = RgbaImage::new(80, 80);
+            draw_styled_text(&mut image, feature, 10, 10, "I", &style);
+            image
+        };
+        let plain = draw(&feature("kind=\"Text\"\nx=0\ny=0\nw=80\nh=80"));
+        assert!(!pixels_of(&plain, white).is_empty());
+        assert!(pixels_of(&plain, red).is_empty() && pixels_of(&plain, blue).is_empty());
+        let styled = draw(&feature(
+            "kind=\"Text\"\nx=0\ny=0\nw=80\nh=80\noutline_width=2\n\
+             outline_color=[255, 0, 0, 255]\nshadow_color=[0, 0, 255, 255]\nshadow_offset=[6, 6]",
+        ));
+        let (text, outline, shadow) =
+            (pixels_of(&styled, white), pixels_of(&styled, red), pixels_of(&styled, blue));
+        assert!(!text.is_empty() && !outline.is_empty() && !shadow.is_empty());
+        //the outline surrounds the text, and the shadow falls below and to the right of both
+        let bottom = |pixels: &[(u32, u32)]| pixels.iter().map(|&(_, y)| y).max().unwrap();
+        let right = |pixels: &[(u32, u32)]| pixels.iter().map(|&(x, _)| x).max().unwrap();
+        assert!(bottom(&outline) > bottom(&text) && right(&outline) > right(&text));
+        assert!(bottom(&shadow) > bottom(&outline) && right(&shadow) > right(&outline));
+    }
+    #[test]
+    fn slants_text() {
+        let white = [255, 255, 255, 255];
+        let font = fonts::default_font();
+        let style = TextStyle {
+            font: &*font,
+            scale: Scale { x: 40.0, y: 40.0 },
+            color: white,
+            bold: 0,
+        };
+        let feature = feature("kind=\"Text\"\nx=0\ny=0\nw=80\nh=80");
+        let draw = |size: u32, slanted: bool| {
+            let mut image = RgbaImage::new(size, size);
+            if slanted {
+                draw_slanted_text(&mut image, &feature, 20, 20, "I", &style);
+            } else {
+                draw_styled_text(&mut image, &feature, 20, 20, "I", &style);
+            }
+            pixels_of(&image, white)
+        };
+        let (upright, slanted) = (draw(80, false), draw(80, true));
+        assert!(!slanted.is_empty());
+        //the top of the letter leans right of where it'd be upright
+        let top_left = |pixels: &[(u32, u32)]| {
+            let top = pixels.iter().map(|&(_, y)| y).min().unwrap();
+            pixels.iter().filter(|&&(_, y)| y == top).map(|&(x, _)| x).min().unwrap()
+        };
+        assert!(top_left(&slanted) > top_left(&upright));
+        //how big the image is around the text doesn't change how it's drawn
+        assert_eq!(draw(200, true), slanted);
+    }
+    #[test]
+    fn rotates_around_pivot() {
+        let opaque = |image: &RgbaImage, x: u32, y: u32| image.get_pixel(x, y).data[3] > 128;
+        //a filled in square, rotated a quarter turn
+        let rotate = |pivot: &str| {
+            let mut image = RgbaImage::new(40, 40);
+            for x in 10..20 {
+                for y in 10..20 {
+                    image.put_pixel(x, y, Rgba([255, 0, 0, 255]));
+                }
+            }
+            let fields = format!("kind=\"Image\"\nx=10\ny=10\nw=10\nh=10\nrotation=90.0\n{}", pivot);
+            transform_feature(image, &feature(fields.as_str()))
+        };
+        //around its center it stays where it was
+        let center = rotate("pivot=\"Center\"");
+        assert!(opaque(&center, 15, 15) && opaque(&center, 11, 18) && opaque(&center, 18, 11));
+        assert!(!opaque(&center, 5, 15) && !opaque(&center, 15, 5));
+        //around its top-left corner it swings out to one side of that corner, whichever way the
+        //rotation goes
+        for top_left in &[rotate(""), rotate("pivot=\"TopLeft\"")] {
+            assert!(!opaque(top_left, 15, 15));
+            assert!(opaque(top_left, 5, 15) != opaque(top_left, 15, 5));
+        }
+        //an explicit point works the same way
+        let point = rotate("pivot=[20.0, 20.0]");
+        assert!(!opaque(&point, 15, 15));
+        assert!(opaque(&point, 25, 15) != opaque(&point, 15, 25));
+    }
+    #[test]
+    fn places_images_by_fit_and_anchor() {
+        //a square image in a wide rect
+        let image = RgbaImage::new(200, 200);
+        let place = |fit: &str, anchor: &str| {
+            let fields = format!(
+                "kind=\"Image\"\nx=0\ny=0\nw=100\nh=50\nfit=\"{}\"\nanchor=\"{}\"",
+                fit,
+                anchor
+            );
+            feature(fields.as_str()).place_image(&image)
+        };
+        //contain leaves room at the sides
+        assert_eq!(place("Contain", "TopLeft"), ((50, 50), (0, 0)));
+        assert_eq!(place("Contain", "Center"), ((50, 50), (25, 0)));
+        assert_eq!(place("Contain", "BottomRight"), ((50, 50), (50, 0)));
+        //cover crops the top and bottom
+        assert_eq!(place("Cover", "TopLeft"), ((100, 100), (0, 0)));
+        assert_eq!(place("Cover", "Center"), ((100, 100), (0, -25)));
+        assert_eq!(place("Cover", "Bottom"), ((100, 100), (0, -50)));
+        //none keeps the size, so it overflows on both sides
+        assert_eq!(place("None", "TopLeft"), ((200, 200), (0, 0)));
+        assert_eq!(place("None", "Center"), ((200, 200), (-50, -75)));
+        assert_eq!(place("None", "BottomRight"), ((200, 200), (-100, -150)));
+        assert_eq!(place("Stretch", "BottomRight"), ((100, 50), (0, 0)));
+        //contain and the center are the defaults
+        let default = feature("kind=\"Image\"\nx=0\ny=0\nw=100\nh=50").place_image(&image);
+        assert_eq!(default, ((50, 50), (25, 0)));
+    }
+    #[test]
+    fn refuses_huge_images_from_the_header() {
+        //a PPM header, with none of the pixels it promises
+        let data = b"P6\n5000 5000\n255\n";
+        assert_eq!(image_dimensions(data).unwrap(), (5000, 5000));
+        match Template::decode_frames(data) {
+            Err(Error::Invalid(message)) => assert!(message.contains("too big")),
+            _ => panic!("expected the image to be refused"),
+        }
+    }
+    #[test]
+    fn render_budget() {
+        let mut budget = 0;
+        assert!(spend(&mut budget, MAX_RENDER_PIXELS).is_ok());
+        assert!(spend(&mut budget, 1).is_err());
+    }
+    #[test]
+    fn either_features_only_take_links() {
+        let schemes = vec!["http".to_owned(), "https".to_owned()];
+        assert!(either_link("https://example.com/a.png", &schemes).is_some());
+        //captions that happen to look like a scheme and a path stay text
+        assert!(either_link("Me: when the meme is dank", &schemes).is_none());
+        assert!(either_link("POV: you", &schemes).is_none());
+        assert!(either_link("nobody:", &schemes).is_none());
+        assert!(either_link("just words", &schemes).is_none());
+    }
 }